@@ -30,6 +30,46 @@ impl<'elf> Elf<'elf> {
         };
         unsafe { core::slice::from_raw_parts(base, header.program_header_count as usize) }
     }
+
+    pub fn section_headers(&self) -> &[SectionHeader] {
+        let header = self.header();
+        assert!(header.section_header_size as usize == core::mem::size_of::<SectionHeader>());
+        let base = unsafe {
+            self.bytes
+                .as_ptr()
+                .add(header.section_header_offset as usize)
+                .cast::<SectionHeader>()
+        };
+        unsafe { core::slice::from_raw_parts(base, header.section_header_count as usize) }
+    }
+
+    fn section_bytes(&self, section: &SectionHeader) -> Option<&'elf [u8]> {
+        let start = section.offset as usize;
+        self.bytes.get(start..start + section.size as usize)
+    }
+}
+
+const SECTION_TYPE_SYMTAB: u32 = 2;
+
+/// The symbol table of the kernel and the string table its names point into.
+#[derive(Debug)]
+pub struct Symbols<'elf> {
+    pub symtab: &'elf [u8],
+    pub strtab: &'elf [u8],
+}
+
+pub fn find_symbols(elf: &[u8]) -> Option<Symbols> {
+    let elf = Elf { bytes: elf };
+    let section_headers = elf.section_headers();
+    let symtab = section_headers
+        .iter()
+        .find(|section| section.ty == SECTION_TYPE_SYMTAB)?;
+    // The link field of a symbol table is the index of its string table
+    let strtab = section_headers.get(symtab.link as usize)?;
+    Some(Symbols {
+        symtab: elf.section_bytes(symtab)?,
+        strtab: elf.section_bytes(strtab)?,
+    })
 }
 
 pub fn mount_kernel<F: FrameAllocator>(
@@ -168,12 +208,14 @@ impl TryFrom<&[u8]> for ProgramHeader {
 #[derive(Clone, Copy, Debug)]
 #[repr(C)]
 pub struct SectionHeader {
-    ty: u32,
-    flags: u32,
-    offset: u64,
-    virtual_address: u64,
-    physical_address: u64,
-    segment_image_size: u64,
-    segment_mem_size: u64,
-    align: u64,
+    pub name: u32,
+    pub ty: u32,
+    pub flags: u64,
+    pub virtual_address: u64,
+    pub offset: u64,
+    pub size: u64,
+    pub link: u32,
+    pub info: u32,
+    pub align: u64,
+    pub entry_size: u64,
 }
@@ -16,6 +16,7 @@ use core::fmt::Write;
 
 use bootloader_api::AllocatedFrameRange;
 use bootloader_api::BootInfo;
+use bootloader_api::KernelSymbols;
 use bootloader_api::MemoryRegion;
 use bootloader_api::MemoryRegionType;
 use common::addr::PhysAddr;
@@ -31,11 +32,13 @@ use uefi::services::boot::MemoryType;
 use uefi::services::filesystem::FileSystem;
 use uefi::string::String16;
 use x86_64::control::Cr3;
+use x86_64::cpuid::CpuFeatures;
 use x86_64::paging::MappedPageTable;
 use x86_64::paging::PageTable;
 use x86_64::paging::PageTableFrameOffsetMapper;
 
 use crate::allocator::BumpAllocator;
+use crate::elf::find_symbols;
 use crate::elf::mount_kernel;
 
 #[macro_export]
@@ -76,6 +79,7 @@ pub extern "efiapi" fn efi_main(
     core::mem::forget(memory_map);
     let mut loaded_segments = [None; 8];
     let kernel = mount_kernel(&kernel_executable, &mut loaded_segments, &bump_allocator).unwrap();
+    let kernel_symbols = copy_kernel_symbols(&kernel_executable, &bump_allocator);
     core::mem::forget(kernel_executable);
 
     // Exit UEFI boot services
@@ -104,6 +108,10 @@ pub extern "efiapi" fn efi_main(
         .unwrap();
     const GB: u64 = 1024 * 1024 * 1024;
     const UPPER_HALF: u64 = 0xffff_8000_0000_0000;
+    assert!(
+        CpuFeatures::detect().pages_1gb,
+        "1 GiB pages are required for the direct map"
+    );
     for i in 0..max_addr.div_ceil(GB) {
         // Temporary identity map
         mapped_page_table
@@ -215,11 +223,11 @@ pub extern "efiapi" fn efi_main(
             len: boot_info_allocated_frame_ranges.len(),
         },
         rsdp,
+        kernel_symbols,
     };
 
     // Set new page table
-    // TODO: make it take a phys addr instead of u64
-    Cr3::write(pml4_frame.as_u64());
+    unsafe { Cr3::write(pml4_frame, 0) };
     sprintln!("setting cr3 to {:x}", pml4_frame.as_u64());
 
     sprintln!("Bootloader is launching kernel...");
@@ -273,6 +281,37 @@ fn read_kernel_executable<'uefi>(
     Ok(buffer)
 }
 
+// The executable is freed along with the rest of the boot services memory, so
+// the symbols are copied to frames the kernel won't reuse
+fn copy_kernel_symbols(elf: &[u8], bump_allocator: &BumpAllocator) -> KernelSymbols {
+    let Some(symbols) = find_symbols(elf) else {
+        sprintln!("Kernel has no symbol table, backtraces won't be symbolized");
+        return KernelSymbols {
+            symtab: 0,
+            symtab_len: 0,
+            strtab: 0,
+            strtab_len: 0,
+        };
+    };
+
+    KernelSymbols {
+        symtab: copy_to_frames(symbols.symtab, bump_allocator).as_u64(),
+        symtab_len: symbols.symtab.len(),
+        strtab: copy_to_frames(symbols.strtab, bump_allocator).as_u64(),
+        strtab_len: symbols.strtab.len(),
+    }
+}
+
+fn copy_to_frames(bytes: &[u8], bump_allocator: &BumpAllocator) -> PhysAddr {
+    let frames = bytes.len().div_ceil(4096).max(1);
+    let frame = bump_allocator.allocate_frames(frames).unwrap();
+    frame
+        .as_virt_ident()
+        .as_slice_mut::<u8>(bytes.len())
+        .copy_from_slice(bytes);
+    frame
+}
+
 fn optimize_memory_map<'uefi>(
     memory_map: &MemoryMap<&UefiAllocator<'uefi>>,
     bump_allocator: &BumpAllocator,
@@ -11,6 +11,8 @@ pub struct BootInfo {
     pub allocated_frame_ranges: AllocatedFrameRanges,
     // acpi rsdp
     pub rsdp: *const core::ffi::c_void,
+    // symbol table of the kernel executable, for backtraces
+    pub kernel_symbols: KernelSymbols,
 }
 
 impl fmt::Debug for BootInfo {
@@ -20,6 +22,7 @@ impl fmt::Debug for BootInfo {
             .field("memory_regions", &&self.memory_regions[..])
             .field("allocated_frame_ranges", &&self.allocated_frame_ranges[..])
             .field("rsdp", &self.rsdp)
+            .field("kernel_symbols", &self.kernel_symbols)
             .finish()
     }
 }
@@ -36,6 +39,20 @@ pub struct Kernel {
     pub stack_end: u64,
 }
 
+/// The `.symtab` and `.strtab` sections of the kernel executable, copied to
+/// frames listed in the allocated frame ranges. Both are empty if the kernel
+/// was stripped.
+#[derive(Debug)]
+#[repr(C)]
+pub struct KernelSymbols {
+    /// Physical address of the symbol table, an array of ELF64 symbols
+    pub symtab: u64,
+    pub symtab_len: usize,
+    /// Physical address of the string table the symbol names point into
+    pub strtab: u64,
+    pub strtab_len: usize,
+}
+
 #[derive(Debug)]
 #[repr(C)]
 pub struct MemoryRegions {
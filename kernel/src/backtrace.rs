@@ -0,0 +1,217 @@
+use core::fmt;
+use core::fmt::Write;
+
+use bootloader_api::KernelSymbols;
+use common::addr::PhysAddr;
+use sync::Once;
+use x86_64::paging::PageTableFrameMapper;
+
+use crate::exception::is_mapped;
+use crate::FRAME_OFFSET_MAPPER;
+
+// Stops walking corrupted stacks eventually
+const MAX_FRAMES: usize = 64;
+
+const SYMBOL_TYPE_FUNC: u8 = 2;
+
+static SYMBOLS: Once<Symbols> = Once::new();
+
+// An ELF64 symbol table entry
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct Symbol {
+    name: u32,
+    info: u8,
+    other: u8,
+    section_index: u16,
+    value: u64,
+    size: u64,
+}
+
+struct Symbols {
+    symtab: &'static [Symbol],
+    strtab: &'static [u8],
+}
+
+impl Symbols {
+    // Finds the function containing `addr` and the offset of `addr` into it
+    fn resolve(&self, addr: u64) -> Option<(&'static str, u64)> {
+        let symbol = self.symtab.iter().find(|symbol| {
+            symbol.info & 0xf == SYMBOL_TYPE_FUNC
+                && (symbol.value..symbol.value + symbol.size).contains(&addr)
+        })?;
+        let name = self.strtab.get(symbol.name as usize..)?;
+        let len = name.iter().position(|&byte| byte == 0)?;
+        let name = core::str::from_utf8(&name[..len]).ok()?;
+        Some((name, addr - symbol.value))
+    }
+}
+
+/// Makes the symbols the bootloader copied out of the kernel executable
+/// available to backtraces, without them only addresses are printed.
+pub fn init(kernel_symbols: &KernelSymbols) {
+    if kernel_symbols.symtab_len == 0 {
+        return;
+    }
+
+    let symtab = FRAME_OFFSET_MAPPER
+        .frame_to_page(PhysAddr::new(kernel_symbols.symtab))
+        .as_slice::<Symbol>(kernel_symbols.symtab_len / core::mem::size_of::<Symbol>());
+    let strtab = FRAME_OFFSET_MAPPER
+        .frame_to_page(PhysAddr::new(kernel_symbols.strtab))
+        .as_slice::<u8>(kernel_symbols.strtab_len);
+    SYMBOLS.call_once(|| Symbols { symtab, strtab });
+}
+
+/// Prints the frame at `rip` followed by the frames of its callers, found by
+/// following the saved frame pointers starting at `rbp`.
+pub fn print(serial: &mut impl Write, rip: u64, rbp: u64) -> fmt::Result {
+    writeln!(serial, "Backtrace:")?;
+    print_frame(serial, 0, rip, rip)?;
+    walk(serial, rbp, 1)
+}
+
+/// Prints the callers of the calling function.
+#[inline(never)]
+pub fn print_current(serial: &mut impl Write) -> fmt::Result {
+    let rbp: u64;
+    unsafe {
+        core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    writeln!(serial, "Backtrace:")?;
+    walk(serial, rbp, 0)
+}
+
+// Every frame starts with the frame pointer of the caller followed by the
+// return address, which requires the kernel to be built with frame pointers
+fn walk(serial: &mut impl Write, mut rbp: u64, first_depth: usize) -> fmt::Result {
+    for depth in first_depth..MAX_FRAMES {
+        if rbp == 0 || !rbp.is_multiple_of(8) || !is_mapped(rbp) || !is_mapped(rbp + 15) {
+            break;
+        }
+
+        let (caller_rbp, return_address) = unsafe {
+            let frame = rbp as *const u64;
+            (frame.read(), frame.add(1).read())
+        };
+        if return_address == 0 {
+            break;
+        }
+
+        // The return address is the instruction after the call, which might
+        // belong to the next function if the call was the last instruction
+        print_frame(serial, depth, return_address, return_address - 1)?;
+
+        // The stack grows down, so callers always have higher frame pointers
+        if caller_rbp <= rbp {
+            break;
+        }
+        rbp = caller_rbp;
+    }
+
+    Ok(())
+}
+
+fn print_frame(serial: &mut impl Write, depth: usize, addr: u64, lookup: u64) -> fmt::Result {
+    let symbol = SYMBOLS.get().and_then(|symbols| symbols.resolve(lookup));
+    match symbol {
+        Some((name, offset)) => writeln!(
+            serial,
+            "  {:2}: {:#018x} {}+{:#x}",
+            depth,
+            addr,
+            Demangle(name),
+            offset + (addr - lookup)
+        ),
+        None => writeln!(serial, "  {:2}: {:#018x} <unknown>", depth, addr),
+    }
+}
+
+// Demangles legacy Rust symbols, `_ZN` followed by length prefixed path
+// components and a hash, other names are printed as they are
+struct Demangle<'a>(&'a str);
+
+impl fmt::Display for Demangle<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(mut rest) = self.0.strip_prefix("_ZN") else {
+            return f.write_str(self.0);
+        };
+
+        let mut first = true;
+        loop {
+            let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+            let Some(len) = rest[..digits].parse::<usize>().ok() else {
+                break;
+            };
+            let Some(component) = rest.get(digits..digits + len) else {
+                break;
+            };
+            rest = &rest[digits + len..];
+
+            let is_hash = component.len() == 17
+                && component.starts_with('h')
+                && component[1..].bytes().all(|byte| byte.is_ascii_hexdigit());
+            if rest == "E" && is_hash {
+                return Ok(());
+            }
+
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            write_component(f, component)?;
+        }
+
+        // Not something we understand, show what is left
+        f.write_str(rest)
+    }
+}
+
+fn write_component(f: &mut fmt::Formatter<'_>, component: &str) -> fmt::Result {
+    // Components that would start with an escape are prefixed with an underscore
+    let mut rest = match component.strip_prefix("_$") {
+        Some(_) => &component[1..],
+        None => component,
+    };
+
+    while !rest.is_empty() {
+        if let Some(escaped) = rest.strip_prefix('$') {
+            let Some(end) = escaped.find('$') else {
+                return f.write_str(rest);
+            };
+            let escape = &escaped[..end];
+            match escape {
+                "SP" => f.write_char('@')?,
+                "BP" => f.write_char('*')?,
+                "RF" => f.write_char('&')?,
+                "LT" => f.write_char('<')?,
+                "GT" => f.write_char('>')?,
+                "LP" => f.write_char('(')?,
+                "RP" => f.write_char(')')?,
+                "C" => f.write_char(',')?,
+                _ => match escape
+                    .strip_prefix('u')
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32)
+                {
+                    Some(c) => f.write_char(c)?,
+                    None => write!(f, "${}$", escape)?,
+                },
+            }
+            rest = &escaped[end + 1..];
+        } else if let Some(after) = rest.strip_prefix("..") {
+            f.write_str("::")?;
+            rest = after;
+        } else {
+            let end = rest
+                .char_indices()
+                .skip(1)
+                .find(|(_, c)| *c == '$' || *c == '.')
+                .map_or(rest.len(), |(index, _)| index);
+            f.write_str(&rest[..end])?;
+            rest = &rest[end..];
+        }
+    }
+
+    Ok(())
+}
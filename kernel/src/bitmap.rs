@@ -1,6 +1,7 @@
-use alloc::raw_vec::RawVec;
 use core::fmt;
 
+use collections::raw_vec::RawVec;
+
 pub struct Bitmap {
     vec: RawVec<u64>,
     len: usize,
@@ -0,0 +1,374 @@
+use core::fmt;
+use core::fmt::Write;
+
+use common::addr::PhysAddr;
+use serial::SerialPort;
+use serial::COM1_BASE;
+use x86_64::control::Cr0;
+use x86_64::control::Cr2;
+use x86_64::control::Cr3;
+use x86_64::control::Cr4;
+use x86_64::paging::PageTableFrameMapper;
+
+use crate::backtrace;
+use crate::per_cpu;
+use crate::percpu;
+use crate::FRAME_OFFSET_MAPPER;
+
+pub const EXCEPTIONS: usize = 32;
+
+// Vectors for which the cpu pushes an error code: #DF, #TS, #NP, #SS, #GP,
+// #PF, #AC, #CP, #VC and #SX
+const ERROR_CODE_VECTORS: u32 = 0x6022_7d00;
+
+// Each stub is aligned to `STUB_SIZE` bytes, so that the stub of a vector can
+// be found from its index
+const STUB_SIZE: u64 = 16;
+
+const DEBUG: u8 = 1;
+const NMI: u8 = 2;
+const BREAKPOINT: u8 = 3;
+const INVALID_TSS: u8 = 10;
+const SEGMENT_NOT_PRESENT: u8 = 11;
+const STACK_SEGMENT_FAULT: u8 = 12;
+const GENERAL_PROTECTION: u8 = 13;
+const PAGE_FAULT: u8 = 14;
+
+// Bytes of the faulting instruction to print, the longest x86 instruction
+const INSTRUCTION_BYTES: u64 = 15;
+
+per_cpu! {
+    // Set while a report is being printed, so that a fault in the reporting
+    // code doesn't recurse forever
+    static REPORTING: bool = false;
+}
+
+// (mnemonic, description) of every exception vector
+const NAMES: [(&str, &str); EXCEPTIONS] = [
+    ("#DE", "Divide error"),
+    ("#DB", "Debug"),
+    ("NMI", "Non-maskable interrupt"),
+    ("#BP", "Breakpoint"),
+    ("#OF", "Overflow"),
+    ("#BR", "Bound range exceeded"),
+    ("#UD", "Invalid opcode"),
+    ("#NM", "Device not available"),
+    ("#DF", "Double fault"),
+    ("-", "Coprocessor segment overrun"),
+    ("#TS", "Invalid TSS"),
+    ("#NP", "Segment not present"),
+    ("#SS", "Stack segment fault"),
+    ("#GP", "General protection"),
+    ("#PF", "Page fault"),
+    ("-", "Reserved"),
+    ("#MF", "x87 floating point"),
+    ("#AC", "Alignment check"),
+    ("#MC", "Machine check"),
+    ("#XM", "SIMD floating point"),
+    ("#VE", "Virtualization"),
+    ("#CP", "Control protection"),
+    ("-", "Reserved"),
+    ("-", "Reserved"),
+    ("-", "Reserved"),
+    ("-", "Reserved"),
+    ("-", "Reserved"),
+    ("-", "Reserved"),
+    ("#HV", "Hypervisor injection"),
+    ("#VC", "VMM communication"),
+    ("#SX", "Security"),
+    ("-", "Reserved"),
+];
+
+/// The registers at the time of the exception, in the order they are pushed
+/// by the stubs and the cpu.
+#[derive(Debug)]
+#[repr(C)]
+pub struct ExceptionContext {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    // Zero for exceptions without an error code
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+extern "C" {
+    static exception_stubs: u8;
+}
+
+/// Address of the entry stub of an exception vector.
+pub fn stub(vector: usize) -> u64 {
+    assert!(vector < EXCEPTIONS);
+    core::ptr::addr_of!(exception_stubs) as u64 + vector as u64 * STUB_SIZE
+}
+
+// Called by the stubs below with interrupts disabled
+#[no_mangle]
+extern "C" fn exception_dispatch(context: &mut ExceptionContext) {
+    let mut serial = SerialPort::new(COM1_BASE);
+    if REPORTING.with(|reporting| core::mem::replace(reporting, true)) {
+        let _ = writeln!(
+            serial,
+            "Exception {} at {:#x} while reporting another exception",
+            context.vector, context.rip
+        );
+        halt();
+    }
+
+    let _ = report(&mut serial, context);
+    let _ = backtrace::print(&mut serial, context.rip, context.rbp);
+
+    // Execution can continue after these, everything else is fatal
+    match context.vector as u8 {
+        DEBUG | NMI | BREAKPOINT => REPORTING.with(|reporting| *reporting = false),
+        _ => halt(),
+    }
+}
+
+fn report(serial: &mut impl Write, context: &ExceptionContext) -> fmt::Result {
+    let vector = context.vector as u8;
+    let (mnemonic, description) = NAMES[vector as usize];
+    write!(
+        serial,
+        "Exception {} ({}) on cpu {}",
+        mnemonic,
+        description,
+        percpu::cpu_index()
+    )?;
+    if has_error_code(vector) {
+        write!(serial, ", error code {:#x}", context.error_code)?;
+    }
+    writeln!(serial)?;
+
+    match vector {
+        INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION => {
+            report_selector_error(serial, context.error_code)?
+        }
+        PAGE_FAULT => report_page_fault(serial, context.error_code)?,
+        _ => {}
+    }
+
+    let c = context;
+    writeln!(
+        serial,
+        "  rax {:#018x} rbx {:#018x} rcx {:#018x} rdx {:#018x}",
+        c.rax, c.rbx, c.rcx, c.rdx
+    )?;
+    writeln!(
+        serial,
+        "  rsi {:#018x} rdi {:#018x} rbp {:#018x} rsp {:#018x}",
+        c.rsi, c.rdi, c.rbp, c.rsp
+    )?;
+    writeln!(
+        serial,
+        "  r8  {:#018x} r9  {:#018x} r10 {:#018x} r11 {:#018x}",
+        c.r8, c.r9, c.r10, c.r11
+    )?;
+    writeln!(
+        serial,
+        "  r12 {:#018x} r13 {:#018x} r14 {:#018x} r15 {:#018x}",
+        c.r12, c.r13, c.r14, c.r15
+    )?;
+    writeln!(
+        serial,
+        "  rip {:#018x} rflags {:#010x} cs {:#06x} ss {:#06x}",
+        c.rip, c.rflags, c.cs, c.ss
+    )?;
+    writeln!(
+        serial,
+        "  cr0 {:#018x} cr2 {:#018x} cr3 {:#018x} cr4 {:#018x}",
+        Cr0::read_raw(),
+        Cr2::read_raw(),
+        Cr3::read_raw(),
+        Cr4::read_raw()
+    )?;
+
+    write!(serial, "  instruction bytes")?;
+    if is_mapped(c.rip) && is_mapped(c.rip + INSTRUCTION_BYTES - 1) {
+        for offset in 0..INSTRUCTION_BYTES {
+            let byte = unsafe { ((c.rip + offset) as *const u8).read_volatile() };
+            write!(serial, " {:02x}", byte)?;
+        }
+        writeln!(serial)
+    } else {
+        writeln!(serial, " not mapped")
+    }
+}
+
+fn has_error_code(vector: u8) -> bool {
+    ERROR_CODE_VECTORS & (1 << vector) != 0
+}
+
+fn report_selector_error(serial: &mut impl Write, error_code: u64) -> fmt::Result {
+    if error_code == 0 {
+        return writeln!(serial, "  not caused by a segment selector");
+    }
+
+    // Bit 0 is set when the exception was caused by an event external to the
+    // program, bit 1 when the index refers to the IDT, otherwise bit 2 picks
+    // the LDT over the GDT
+    let table = match (error_code & 0b10 != 0, error_code & 0b100 != 0) {
+        (true, _) => "IDT",
+        (false, false) => "GDT",
+        (false, true) => "LDT",
+    };
+    write!(
+        serial,
+        "  selector index {} in the {}",
+        (error_code >> 3) & 0x1fff,
+        table
+    )?;
+    if error_code & 1 != 0 {
+        write!(serial, ", external event")?;
+    }
+    writeln!(serial)
+}
+
+fn report_page_fault(serial: &mut impl Write, error_code: u64) -> fmt::Result {
+    let present = error_code & (1 << 0) != 0;
+    let write = error_code & (1 << 1) != 0;
+    let user = error_code & (1 << 2) != 0;
+    let reserved = error_code & (1 << 3) != 0;
+    let instruction_fetch = error_code & (1 << 4) != 0;
+
+    let access = match (instruction_fetch, write) {
+        (true, _) => "instruction fetch",
+        (false, true) => "write",
+        (false, false) => "read",
+    };
+    write!(
+        serial,
+        "  {} {} of {:#x}, {}",
+        match user {
+            true => "user",
+            false => "supervisor",
+        },
+        access,
+        Cr2::read_raw(),
+        match present {
+            true => "protection violation",
+            false => "page not present",
+        }
+    )?;
+    if reserved {
+        write!(serial, ", reserved bit set in a paging structure")?;
+    }
+    writeln!(serial)
+}
+
+/// Walks the active page tables to check that reading `addr` won't fault,
+/// for code that inspects memory while reporting a fault.
+pub fn is_mapped(addr: u64) -> bool {
+    let mut table = Cr3::read().pba_pml4;
+    for level in (0..4).rev() {
+        let index = (addr >> (12 + level * 9)) & 0x1ff;
+        let entry = unsafe {
+            FRAME_OFFSET_MAPPER
+                .frame_to_page(PhysAddr::new(table))
+                .as_ptr::<u64>()
+                .add(index as usize)
+                .read_volatile()
+        };
+        if entry & 1 == 0 {
+            return false;
+        }
+
+        // 1 GiB and 2 MiB pages end the walk early
+        if level > 0 && entry & (1 << 7) != 0 {
+            return true;
+        }
+
+        table = entry & 0x000f_ffff_ffff_f000;
+    }
+
+    true
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe { core::arch::asm!("cli", "hlt") };
+    }
+}
+
+// Vectors without an error code push a zero in its place, so that every
+// exception ends up with the same `ExceptionContext` layout. The cpu aligns the
+// stack to 16 bytes before pushing the 5 words of the interrupt frame, which
+// together with the error code, the vector and 15 registers keeps it aligned
+// for the call.
+core::arch::global_asm!(
+    ".pushsection .text.exception_stubs, \"ax\"",
+    ".global exception_stubs",
+    ".p2align 4",
+    "exception_stubs:",
+    ".set exception_vector, 0",
+    ".rept {exceptions}",
+    ".p2align 4",
+    ".if (({error_code_vectors} >> exception_vector) & 1) == 0",
+    "pushq $0",
+    ".endif",
+    "pushq $exception_vector",
+    "jmp exception_common",
+    ".set exception_vector, exception_vector + 1",
+    ".endr",
+    "exception_common:",
+    // Above the vector, the error code and the saved rip
+    crate::swapgs_if_user!(24),
+    "pushq %rax",
+    "pushq %rbx",
+    "pushq %rcx",
+    "pushq %rdx",
+    "pushq %rsi",
+    "pushq %rdi",
+    "pushq %rbp",
+    "pushq %r8",
+    "pushq %r9",
+    "pushq %r10",
+    "pushq %r11",
+    "pushq %r12",
+    "pushq %r13",
+    "pushq %r14",
+    "pushq %r15",
+    "movq %rsp, %rdi",
+    "cld",
+    "call exception_dispatch",
+    "popq %r15",
+    "popq %r14",
+    "popq %r13",
+    "popq %r12",
+    "popq %r11",
+    "popq %r10",
+    "popq %r9",
+    "popq %r8",
+    "popq %rbp",
+    "popq %rdi",
+    "popq %rsi",
+    "popq %rdx",
+    "popq %rcx",
+    "popq %rbx",
+    "popq %rax",
+    // Drop the vector and the error code
+    "addq $16, %rsp",
+    crate::swapgs_if_user!(8),
+    "iretq",
+    ".popsection",
+    exceptions = const EXCEPTIONS,
+    error_code_vectors = const ERROR_CODE_VECTORS,
+    options(att_syntax)
+);
@@ -1,99 +1,427 @@
-use core::fmt::Write;
+use core::fmt;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
 
-use serial::SerialPort;
-use serial::COM1_BASE;
-use x86_64::control::Cr2;
-use x86_64::idt::IdtEntry;
+use sync::Once;
+use sync::RwLock;
+use sync::SpinLock;
+use x86_64::idt::InterruptDescriptorTable;
+use x86_64::port::PortReadOnly;
 
+use crate::exception;
+use crate::ioapic;
+use crate::sched;
 use crate::sprintln;
-use crate::DescriptorTablePointer;
+use crate::tss;
+use crate::wait::Semaphore;
 use crate::LAPIC;
 
-pub fn init(idt: &mut [IdtEntry]) {
-    // entry point, index 1 of gdt  (1 << 3) = 8, options(0x8f00) = [present, gate type is trap gate]
-    idt[0x00] = IdtEntry::new(interrupt_div0 as _, 0x8, 0x8e00);
-    idt[0x03] = IdtEntry::new(interrupt_breakpoint as _, 0x8, 0x8e00);
-    idt[0x08] = IdtEntry::new(interrupt_dbl as _, 0x8, 0x8e00);
-    idt[0x0e] = IdtEntry::new(interrupt_page_fault as _, 0x8, 0x8e00);
-    idt[0x20] = IdtEntry::new(interrupt_timer as _, 0x8, 0x8e00);
-    idt[0x21] = IdtEntry::new(interrupt_kb as _, 0x8, 0x8e00);
-    unsafe {
-        let ptr = DescriptorTablePointer {
-            limit: (core::mem::size_of_val(idt) - 1) as u16,
-            base: idt.as_ptr() as _,
+/// Vector the local apics deliver spurious interrupts on.
+pub const SPURIOUS_VECTOR: u8 = 0x99;
+
+// Vectors below this are reserved for cpu exceptions
+const FIRST_DEVICE_VECTOR: u8 = 0x20;
+const DEVICE_VECTORS: usize = 256 - FIRST_DEVICE_VECTOR as usize;
+// Handlers per vector, more than one when devices share an irq line
+const MAX_SHARED_HANDLERS: usize = 4;
+
+// Shared by all cpus, the application processors load it in `load`
+static IDT: Once<&'static InterruptDescriptorTable> = Once::new();
+
+static VECTOR_ALLOCATOR: SpinLock<VectorAllocator> = SpinLock::new(VectorAllocator::new());
+static VECTORS: [Vector; DEVICE_VECTORS] = [const { Vector::new() }; DEVICE_VECTORS];
+static SPURIOUS_COUNT: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub enum InterruptError {
+    // Exception vectors and the spurious vector can't be handed out
+    ReservedVector(u8),
+    // Handlers can only be registered for vectors from `allocate_vector`
+    UnallocatedVector(u8),
+    NoFreeVector,
+    TooManyHandlers(u8),
+    // Vectors can only be freed once all their handlers are unregistered
+    VectorInUse(u8),
+}
+
+impl fmt::Display for InterruptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InterruptError::ReservedVector(vector) => write!(f, "vector {:#x} is reserved", vector),
+            InterruptError::UnallocatedVector(vector) => {
+                write!(f, "vector {:#x} is not allocated", vector)
+            }
+            InterruptError::NoFreeVector => write!(f, "no free vector"),
+            InterruptError::TooManyHandlers(vector) => {
+                write!(f, "vector {:#x} has too many handlers", vector)
+            }
+            InterruptError::VectorInUse(vector) => {
+                write!(f, "vector {:#x} still has handlers", vector)
+            }
+        }
+    }
+}
+
+/// Identifies a registered handler, see `unregister`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HandlerId {
+    vector: u8,
+    slot: usize,
+}
+
+/// Called with the context data it was registered with, returns whether the
+/// interrupt came from its device. All handlers of a vector are called, so
+/// that devices sharing a level triggered line are all serviced.
+pub type Handler = fn(data: usize) -> bool;
+
+#[derive(Clone, Copy)]
+struct RegisteredHandler {
+    handler: Handler,
+    data: usize,
+}
+
+struct Vector {
+    handlers: RwLock<[Option<RegisteredHandler>; MAX_SHARED_HANDLERS]>,
+    count: AtomicU64,
+    // Interrupts that none of the handlers claimed
+    unhandled: AtomicU64,
+}
+
+impl Vector {
+    const fn new() -> Self {
+        Self {
+            handlers: RwLock::new([None; MAX_SHARED_HANDLERS]),
+            count: AtomicU64::new(0),
+            unhandled: AtomicU64::new(0),
+        }
+    }
+}
+
+struct VectorAllocator {
+    // One bit per vector, set when the vector is taken
+    used: [u64; 4],
+}
+
+impl VectorAllocator {
+    const fn new() -> Self {
+        let mut allocator = Self { used: [0; 4] };
+        allocator.used[0] = u32::MAX as u64;
+        allocator.used[SPURIOUS_VECTOR as usize / 64] |= 1 << (SPURIOUS_VECTOR % 64);
+        allocator
+    }
+
+    fn allocate(&mut self) -> Option<u8> {
+        let (index, word) = self
+            .used
+            .iter_mut()
+            .enumerate()
+            .find(|(_, word)| **word != u64::MAX)?;
+        let bit = word.trailing_ones();
+        *word |= 1 << bit;
+        Some((index * 64) as u8 + bit as u8)
+    }
+
+    fn is_allocated(&self, vector: u8) -> bool {
+        self.used[vector as usize / 64] & (1 << (vector % 64)) != 0
+    }
+
+    fn free(&mut self, vector: u8) {
+        self.used[vector as usize / 64] &= !(1 << (vector % 64));
+    }
+}
+
+pub fn init(idt: &'static mut InterruptDescriptorTable) {
+    *idt = InterruptDescriptorTable::new();
+    // The exceptions and every other vector go through stubs that end up in
+    // `exception_dispatch` and `interrupt_dispatch` respectively
+    let stubs = core::ptr::addr_of!(interrupt_stubs) as u64;
+    for vector in 0..=u8::MAX {
+        let stub = match vector < FIRST_DEVICE_VECTOR {
+            true => exception::stub(vector as usize),
+            false => stubs + (vector - FIRST_DEVICE_VECTOR) as u64 * STUB_SIZE,
         };
-        core::arch::asm!("cli");
-        core::arch::asm!("lidt [{}]", in(reg) &ptr);
-        core::arch::asm!("sti");
+        unsafe { idt.entry_mut(vector).set_handler_addr(stub) };
     }
+    idt.double_fault
+        .options_mut()
+        .set_ist_index(tss::DOUBLE_FAULT_IST);
+    idt.non_maskable_interrupt
+        .options_mut()
+        .set_ist_index(tss::NMI_IST);
+    idt.machine_check
+        .options_mut()
+        .set_ist_index(tss::MACHINE_CHECK_IST);
+
+    IDT.call_once(|| idt);
+    load();
 }
 
-#[derive(Debug)]
-#[repr(C)]
-struct InterruptStackFrame {
-    pub instruction_pointer: u64,
-    pub code_segment: u64,
-    pub cpu_flags: u64,
-    pub stack_pointer: u64,
-    pub stack_segment: u64,
+/// Loads the IDT set up by `init` on the current cpu. Interrupts are left as
+/// they are, the caller enables them once the cpu is fully set up.
+pub fn load() {
+    IDT.get().expect("IDT not initialized").load();
+}
+
+/// Hands out a vector that no other driver uses.
+pub fn allocate_vector() -> Result<u8, InterruptError> {
+    VECTOR_ALLOCATOR
+        .lock()
+        .allocate()
+        .ok_or(InterruptError::NoFreeVector)
+}
+
+/// Returns a vector from `allocate_vector`, its handlers have to be
+/// unregistered first.
+// No driver is torn down yet, only the tests and the self test free vectors
+#[allow(dead_code)]
+pub fn free_vector(vector: u8) -> Result<(), InterruptError> {
+    if vector < FIRST_DEVICE_VECTOR || vector == SPURIOUS_VECTOR {
+        return Err(InterruptError::ReservedVector(vector));
+    }
+
+    let mut vector_allocator = VECTOR_ALLOCATOR.lock();
+    let handlers = VECTORS[(vector - FIRST_DEVICE_VECTOR) as usize]
+        .handlers
+        .read();
+    if handlers.iter().any(Option::is_some) {
+        return Err(InterruptError::VectorInUse(vector));
+    }
+
+    vector_allocator.free(vector);
+    Ok(())
+}
+
+/// Calls `handler` with `data` whenever `vector` fires, after which the
+/// dispatcher signals the end of interrupt. Handlers run with interrupts
+/// disabled and must not register or unregister handlers of their own vector.
+pub fn register(vector: u8, handler: Handler, data: usize) -> Result<HandlerId, InterruptError> {
+    if vector < FIRST_DEVICE_VECTOR || vector == SPURIOUS_VECTOR {
+        return Err(InterruptError::ReservedVector(vector));
+    }
+
+    // Held until the handler is in place, so that the vector can't be freed
+    // in between
+    let vector_allocator = VECTOR_ALLOCATOR.lock();
+    if !vector_allocator.is_allocated(vector) {
+        return Err(InterruptError::UnallocatedVector(vector));
+    }
+
+    // The lock keeps interrupts disabled, so the dispatcher can't interrupt us
+    // while we hold it
+    let mut handlers = VECTORS[(vector - FIRST_DEVICE_VECTOR) as usize]
+        .handlers
+        .write();
+    let (slot, entry) = handlers
+        .iter_mut()
+        .enumerate()
+        .find(|(_, entry)| entry.is_none())
+        .ok_or(InterruptError::TooManyHandlers(vector))?;
+    *entry = Some(RegisteredHandler { handler, data });
+    Ok(HandlerId { vector, slot })
+}
+
+/// Like `register`, with the context carried by the closure.
+pub fn register_closure<F>(vector: u8, f: &'static F) -> Result<HandlerId, InterruptError>
+where
+    F: Fn() -> bool + Sync,
+{
+    fn call<F: Fn() -> bool>(data: usize) -> bool {
+        let f = unsafe { &*(data as *const F) };
+        f()
+    }
+
+    register(vector, call::<F>, f as *const F as usize)
+}
+
+/// Removes a handler. Once a vector has no handlers left, the global system
+/// interrupt routed to it is masked.
+#[allow(dead_code)]
+pub fn unregister(id: HandlerId) {
+    let mut handlers = VECTORS[(id.vector - FIRST_DEVICE_VECTOR) as usize]
+        .handlers
+        .write();
+    handlers[id.slot] = None;
+    if handlers.iter().any(Option::is_some) {
+        return;
+    }
+
+    drop(handlers);
+    if let Some(gsi) = ioapic::routed_gsi(id.vector) {
+        // The gsi was just found, so an I/O apic handles it
+        ioapic::mask(gsi).unwrap();
+    }
+}
+
+/// Prints how often each vector fired, and how many of those interrupts no
+/// handler claimed.
+pub fn print_statistics() {
+    for (index, vector) in VECTORS.iter().enumerate() {
+        let count = vector.count.load(Ordering::Relaxed);
+        if count > 0 {
+            sprintln!(
+                "Vector {:#x}: {} interrupts, {} unhandled",
+                index + FIRST_DEVICE_VECTOR as usize,
+                count,
+                vector.unhandled.load(Ordering::Relaxed)
+            );
+        }
+    }
+    sprintln!(
+        "Spurious interrupts: {}",
+        SPURIOUS_COUNT.load(Ordering::Relaxed)
+    );
 }
 
-extern "x86-interrupt" fn interrupt_div0(frame: InterruptStackFrame) {
-    let mut serial = SerialPort::new(COM1_BASE);
-    writeln!(serial, "Div 0, frame: {:#x?}", frame).unwrap();
-    loop {}
+// Called by the stubs below with interrupts disabled
+#[no_mangle]
+extern "C" fn interrupt_dispatch(vector: u64) {
+    let vector = vector as u8;
+    if vector == SPURIOUS_VECTOR {
+        // The local apic doesn't expect an end of interrupt for these
+        SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    let entry = &VECTORS[(vector - FIRST_DEVICE_VECTOR) as usize];
+    entry.count.fetch_add(1, Ordering::Relaxed);
+    let handled = entry
+        .handlers
+        .read()
+        .iter()
+        .flatten()
+        .fold(false, |handled, handler| {
+            (handler.handler)(handler.data) | handled
+        });
+    if !handled {
+        entry.unhandled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    LAPIC.with(|lapic| lapic.write_eoi());
+    sched::preempt();
 }
 
-extern "x86-interrupt" fn interrupt_breakpoint(frame: InterruptStackFrame) {
-    let mut serial = SerialPort::new(COM1_BASE);
-    writeln!(serial, "Breakpoint, frame: {:#x?}", frame).unwrap();
+// Each stub is aligned to `STUB_SIZE` bytes, so that the stub of a vector can
+// be found from its index
+const STUB_SIZE: u64 = 16;
+
+extern "C" {
+    static interrupt_stubs: u8;
 }
 
-extern "x86-interrupt" fn interrupt_dbl(frame: InterruptStackFrame, code: u64) {
-    let mut serial = SerialPort::new(COM1_BASE);
-    writeln!(
-        serial,
-        "Double fault, frame: {:#x?}. code: {:#x}",
-        frame, code
-    )
-    .unwrap();
+// The stubs push their vector and jump to a common entry, which saves the
+// registers an extern "C" function may clobber. The cpu aligns the stack to 16
+// bytes before pushing the 5 words of the interrupt frame, so the vector plus
+// 9 registers leave it 8 bytes off for the call.
+core::arch::global_asm!(
+    ".pushsection .text.interrupt_stubs, \"ax\"",
+    ".global interrupt_stubs",
+    ".p2align 4",
+    "interrupt_stubs:",
+    ".set interrupt_vector, {first}",
+    ".rept 256 - {first}",
+    ".p2align 4",
+    "pushq $interrupt_vector",
+    "jmp interrupt_common",
+    ".set interrupt_vector, interrupt_vector + 1",
+    ".endr",
+    "interrupt_common:",
+    // Above the vector and the saved rip
+    crate::swapgs_if_user!(16),
+    "pushq %rax",
+    "pushq %rcx",
+    "pushq %rdx",
+    "pushq %rsi",
+    "pushq %rdi",
+    "pushq %r8",
+    "pushq %r9",
+    "pushq %r10",
+    "pushq %r11",
+    "movq 72(%rsp), %rdi",
+    "subq $8, %rsp",
+    "cld",
+    "call interrupt_dispatch",
+    "addq $8, %rsp",
+    "popq %r11",
+    "popq %r10",
+    "popq %r9",
+    "popq %r8",
+    "popq %rdi",
+    "popq %rsi",
+    "popq %rdx",
+    "popq %rcx",
+    "popq %rax",
+    // Drop the vector
+    "addq $8, %rsp",
+    crate::swapgs_if_user!(8),
+    "iretq",
+    ".popsection",
+    first = const FIRST_DEVICE_VECTOR,
+    options(att_syntax)
+);
+
+const PS2_DATA_PORT: u16 = 0x60;
+
+// Scancodes received by `keyboard` that `keyboard_reader` hasn't printed yet,
+// `SCANCODES_READY` counts them
+static SCANCODES: SpinLock<ScancodeBuffer> = SpinLock::new(ScancodeBuffer::new());
+static SCANCODES_READY: Semaphore = Semaphore::new(0);
+
+struct ScancodeBuffer {
+    scancodes: [u8; 32],
+    head: usize,
+    len: usize,
 }
 
-extern "x86-interrupt" fn interrupt_page_fault(frame: InterruptStackFrame, code: u64) {
-    let mut serial = SerialPort::new(COM1_BASE);
-    writeln!(
-        serial,
-        "Page fault, frame: {:#x?}, code: {:#x}, trying to access: {:#x?}",
-        frame,
-        code,
-        Cr2::read()
-    )
-    .unwrap();
-    loop {}
+impl ScancodeBuffer {
+    const fn new() -> Self {
+        Self {
+            scancodes: [0; 32],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, scancode: u8) -> bool {
+        if self.len == self.scancodes.len() {
+            return false;
+        }
+
+        self.scancodes[(self.head + self.len) % self.scancodes.len()] = scancode;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let scancode = self.scancodes[self.head];
+        self.head = (self.head + 1) % self.scancodes.len();
+        self.len -= 1;
+        Some(scancode)
+    }
 }
 
-static mut COUNT: usize = 0;
-
-extern "x86-interrupt" fn interrupt_timer(frame: InterruptStackFrame) {
-    let mut serial = SerialPort::new(COM1_BASE);
-    writeln!(
-        serial,
-        "{:?} Timer interrupt, frame: {:#x?}",
-        unsafe { COUNT },
-        frame,
-    )
-    .unwrap();
-    unsafe {
-        COUNT += 1;
-        LAPIC.write_eoi();
+/// Handler for the PS/2 keyboard irq, hands the scancode to
+/// `keyboard_reader`.
+pub fn keyboard(_data: usize) -> bool {
+    let scancode = unsafe { PortReadOnly::<u8>::new(PS2_DATA_PORT).read() };
+    // Scancodes are dropped while the reader is behind
+    if SCANCODES.lock().push(scancode) {
+        SCANCODES_READY.release();
     }
+    true
 }
 
-extern "x86-interrupt" fn interrupt_kb(frame: InterruptStackFrame) {
-    let scancode = unsafe { inb(0x60) };
-    print_scancode(scancode);
-    unsafe {
-        LAPIC.write_eoi();
+/// Prints the scancodes of the PS/2 keyboard as they arrive, sleeping in
+/// between. Runs as a thread.
+pub fn keyboard_reader() {
+    loop {
+        SCANCODES_READY.acquire();
+        if let Some(scancode) = SCANCODES.lock().pop() {
+            print_scancode(scancode);
+        }
     }
 }
 
@@ -290,25 +618,40 @@ fn print_scancode(b: u8) {
     sprintln!("{}", string);
 }
 
-/// Write byte to port
-unsafe fn outb(port: u16, data: u8) {
-    unsafe {
-        core::arch::asm!("out dx, al",
-          in("al") data,
-          in("dx") port
-        );
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handler(_: usize) -> bool {
+        true
     }
-}
 
-unsafe fn inb(port: u16) -> u8 {
-    unsafe {
-        // Trust me bro
-        #[allow(unused_assignments)]
-        let mut data = 0;
-        core::arch::asm!("in al, dx",
-          in("dx") port,
-          inout("al") data
-        );
-        data
+    #[test]
+    fn vector_lifecycle() {
+        assert!(matches!(
+            register(FIRST_DEVICE_VECTOR - 1, handler, 0),
+            Err(InterruptError::ReservedVector(_))
+        ));
+
+        let vector = allocate_vector().unwrap();
+        let ids: [_; MAX_SHARED_HANDLERS] =
+            core::array::from_fn(|_| register(vector, handler, 0).unwrap());
+        assert!(matches!(
+            register(vector, handler, 0),
+            Err(InterruptError::TooManyHandlers(_))
+        ));
+
+        for id in ids {
+            assert!(matches!(
+                free_vector(vector),
+                Err(InterruptError::VectorInUse(_))
+            ));
+            unregister(id);
+        }
+        free_vector(vector).unwrap();
+        assert!(matches!(
+            register(vector, handler, 0),
+            Err(InterruptError::UnallocatedVector(_))
+        ));
     }
 }
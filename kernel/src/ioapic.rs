@@ -0,0 +1,281 @@
+use core::fmt;
+
+use acpi::tables::Madt;
+use common::addr::PhysAddr;
+use sync::SpinLock;
+
+use crate::sprintln;
+use crate::VMALLOC;
+
+const MAX_IO_APICS: usize = 8;
+const ISA_IRQS: usize = 16;
+
+// Registers are accessed indirectly, by writing the register index to
+// IOREGSEL and then accessing IOWIN
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+const IOAPICID: u32 = 0x00;
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+// Redirection entry bits
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+static IO_APICS: SpinLock<IoApics> = SpinLock::new(IoApics::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+#[derive(Debug)]
+pub enum IoApicError {
+    // No I/O apic handles the global system interrupt
+    UnknownGsi(u32),
+    // ISA only has irqs 0 to 15
+    InvalidIsaIrq(u8),
+}
+
+impl fmt::Display for IoApicError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoApicError::UnknownGsi(gsi) => write!(f, "no I/O apic handles gsi {}", gsi),
+            IoApicError::InvalidIsaIrq(irq) => write!(f, "{} is not an ISA irq", irq),
+        }
+    }
+}
+
+/// Where an ISA irq ends up after interrupt source overrides are applied.
+#[derive(Clone, Copy, Debug)]
+pub struct IsaIrq {
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger_mode: TriggerMode,
+}
+
+#[derive(Debug)]
+struct IoApic {
+    // Virtual address of the registers
+    base: u64,
+    id: u8,
+    gsi_base: u32,
+    max_redirection_entry: u8,
+}
+
+impl IoApic {
+    /// # Safety
+    ///
+    /// `base` must be the virtual address of the registers of an I/O apic.
+    unsafe fn new(base: u64, gsi_base: u32) -> Self {
+        let mut io_apic = Self {
+            base,
+            id: 0,
+            gsi_base,
+            max_redirection_entry: 0,
+        };
+        io_apic.id = (io_apic.read(IOAPICID) >> 24) as u8 & 0xf;
+        io_apic.max_redirection_entry = (io_apic.read(IOAPICVER) >> 16) as u8;
+        io_apic
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..=self.gsi_base + self.max_redirection_entry as u32).contains(&gsi)
+    }
+
+    fn read_redirection(&self, entry: u8) -> u64 {
+        let register = IOREDTBL + entry as u32 * 2;
+        let low = self.read(register) as u64;
+        let high = self.read(register + 1) as u64;
+        (high << 32) | low
+    }
+
+    fn write_redirection(&self, entry: u8, value: u64) {
+        let register = IOREDTBL + entry as u32 * 2;
+        // Mask the entry while updating it, so that a half written entry never fires
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (value >> 32) as u32);
+        self.write(register, value as u32);
+    }
+
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ((self.base + IOREGSEL) as *mut u32).write_volatile(register);
+            ((self.base + IOWIN) as *const u32).read_volatile()
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            ((self.base + IOREGSEL) as *mut u32).write_volatile(register);
+            ((self.base + IOWIN) as *mut u32).write_volatile(value);
+        }
+    }
+}
+
+struct IoApics {
+    io_apics: [Option<IoApic>; MAX_IO_APICS],
+    isa_irqs: [IsaIrq; ISA_IRQS],
+}
+
+impl IoApics {
+    const fn new() -> Self {
+        let mut isa_irqs = [IsaIrq {
+            gsi: 0,
+            polarity: Polarity::ActiveHigh,
+            trigger_mode: TriggerMode::Edge,
+        }; ISA_IRQS];
+        let mut irq = 0;
+        while irq < ISA_IRQS {
+            isa_irqs[irq].gsi = irq as u32;
+            irq += 1;
+        }
+
+        Self {
+            io_apics: [const { None }; MAX_IO_APICS],
+            isa_irqs,
+        }
+    }
+
+    fn find_vector(&self, vector: u8) -> Option<u32> {
+        self.io_apics.iter().flatten().find_map(|io_apic| {
+            (0..=io_apic.max_redirection_entry)
+                .find(|entry| io_apic.read_redirection(*entry) as u8 == vector)
+                .map(|entry| io_apic.gsi_base + entry as u32)
+        })
+    }
+
+    fn find(&self, gsi: u32) -> Result<(&IoApic, u8), IoApicError> {
+        self.io_apics
+            .iter()
+            .flatten()
+            .find(|io_apic| io_apic.handles(gsi))
+            .map(|io_apic| (io_apic, (gsi - io_apic.gsi_base) as u8))
+            .ok_or(IoApicError::UnknownGsi(gsi))
+    }
+}
+
+/// Maps the registers of every I/O apic in the MADT and masks all of their
+/// interrupts. ISA irqs are identity mapped to global system interrupts
+/// unless the MADT overrides them.
+pub fn init(madt: &Madt) {
+    let mut io_apics = IO_APICS.lock();
+    for (slot, entry) in io_apics.io_apics.iter_mut().zip(madt.io_apics()) {
+        let address = entry.address as u64;
+        let area = VMALLOC
+            .map_mmio(PhysAddr::new(address & !0xfff), 1)
+            .unwrap();
+        let base = area.start().as_u64() + (address & 0xfff);
+        let io_apic = unsafe { IoApic::new(base, entry.global_system_interrupt_base) };
+        for entry in 0..=io_apic.max_redirection_entry {
+            io_apic.write_redirection(entry, REDIRECTION_MASKED);
+        }
+
+        sprintln!(
+            "I/O apic {} at {:#x}, gsi {}..={}",
+            io_apic.id,
+            address,
+            io_apic.gsi_base,
+            io_apic.gsi_base + io_apic.max_redirection_entry as u32
+        );
+        *slot = Some(io_apic);
+    }
+
+    for entry in madt.interrupt_source_overrides() {
+        let Some(isa_irq) = io_apics.isa_irqs.get_mut(entry.source as usize) else {
+            continue;
+        };
+
+        // The bus defaults of ISA are active high and edge triggered
+        isa_irq.gsi = entry.global_system_interrupt;
+        isa_irq.polarity = match entry.polarity() {
+            acpi::tables::Polarity::ActiveLow => Polarity::ActiveLow,
+            _ => Polarity::ActiveHigh,
+        };
+        isa_irq.trigger_mode = match entry.trigger_mode() {
+            acpi::tables::TriggerMode::Level => TriggerMode::Level,
+            _ => TriggerMode::Edge,
+        };
+    }
+}
+
+/// Translates an ISA irq through the interrupt source overrides.
+pub fn isa_irq(irq: u8) -> Result<IsaIrq, IoApicError> {
+    IO_APICS
+        .lock()
+        .isa_irqs
+        .get(irq as usize)
+        .copied()
+        .ok_or(IoApicError::InvalidIsaIrq(irq))
+}
+
+/// Routes a global system interrupt to `vector` on the cpu with local apic
+/// id `destination`, leaving it masked.
+pub fn route(
+    gsi: u32,
+    vector: u8,
+    polarity: Polarity,
+    trigger_mode: TriggerMode,
+    destination: u8,
+) -> Result<(), IoApicError> {
+    let io_apics = IO_APICS.lock();
+    let (io_apic, entry) = io_apics.find(gsi)?;
+
+    let mut value = vector as u64 | REDIRECTION_MASKED | ((destination as u64) << 56);
+    if polarity == Polarity::ActiveLow {
+        value |= REDIRECTION_ACTIVE_LOW;
+    }
+    if trigger_mode == TriggerMode::Level {
+        value |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+
+    io_apic.write_redirection(entry, value);
+    Ok(())
+}
+
+/// Routes an ISA irq to `vector`, with the polarity and trigger mode from the
+/// MADT, leaving it masked.
+pub fn route_isa_irq(irq: u8, vector: u8, destination: u8) -> Result<(), IoApicError> {
+    let isa_irq = isa_irq(irq)?;
+    route(
+        isa_irq.gsi,
+        vector,
+        isa_irq.polarity,
+        isa_irq.trigger_mode,
+        destination,
+    )
+}
+
+/// The global system interrupt routed to `vector`, if any.
+pub fn routed_gsi(vector: u8) -> Option<u32> {
+    IO_APICS.lock().find_vector(vector)
+}
+
+pub fn mask(gsi: u32) -> Result<(), IoApicError> {
+    set_masked(gsi, true)
+}
+
+pub fn unmask(gsi: u32) -> Result<(), IoApicError> {
+    set_masked(gsi, false)
+}
+
+fn set_masked(gsi: u32, masked: bool) -> Result<(), IoApicError> {
+    let io_apics = IO_APICS.lock();
+    let (io_apic, entry) = io_apics.find(gsi)?;
+    let value = io_apic.read_redirection(entry);
+    let value = match masked {
+        true => value | REDIRECTION_MASKED,
+        false => value & !REDIRECTION_MASKED,
+    };
+    io_apic.write_redirection(entry, value);
+    Ok(())
+}
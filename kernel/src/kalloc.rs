@@ -1,82 +1,270 @@
+use core::alloc::AllocError;
 use core::alloc::Allocator;
+use core::alloc::GlobalAlloc;
 use core::alloc::Layout;
+use core::ptr::NonNull;
 
+use common::addr::VirtAddr;
 use common::frame::FrameAllocator;
+use sync::SpinLock;
+use x86_64::paging::PageTableFrameMapper;
 
+use crate::magazine::MagazineCache;
 use crate::slub::SlabCache;
-use crate::spinlock::Mutex;
+use crate::slub::SlabCacheOptions;
+use crate::slub::SlabCacheStats;
+use crate::sprintln;
+use crate::FRAME_OFFSET_MAPPER;
+
+const FRAME_SIZE: usize = 4096;
+
+const fn kmalloc_cache(name: &'static str, size: usize) -> SlabCacheOptions {
+    match Layout::from_size_align(size, size) {
+        Ok(layout) => SlabCacheOptions::new(name, layout),
+        Err(_) => panic!("slab object size must be a power of two"),
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownCacheError;
 
 pub struct KernelAllocator<'f, F: FrameAllocator> {
-    slab_32: Mutex<SlabCache<&'f F>>,
-    slab_64: Mutex<SlabCache<&'f F>>,
-    slab_128: Mutex<SlabCache<&'f F>>,
-    slab_256: Mutex<SlabCache<&'f F>>,
-    slab_512: Mutex<SlabCache<&'f F>>,
-    slab_1k: Mutex<SlabCache<&'f F>>,
-    slab_2k: Mutex<SlabCache<&'f F>>,
+    frame_allocator: &'f F,
+    slab_32: MagazineCache<&'f F>,
+    slab_64: MagazineCache<&'f F>,
+    slab_128: MagazineCache<&'f F>,
+    slab_256: MagazineCache<&'f F>,
+    slab_512: MagazineCache<&'f F>,
+    slab_1k: MagazineCache<&'f F>,
+    named_caches: SpinLock<NamedCacheList<'f, F>>,
+}
+
+// Caches created through `create_cache` are allocated from the kernel
+// allocator itself and linked together so their stats can be dumped
+struct NamedCache<'f, F: FrameAllocator> {
+    cache: SlabCache<&'f F>,
+    next: Option<NonNull<NamedCache<'f, F>>>,
+}
+
+struct NamedCacheList<'f, F: FrameAllocator> {
+    head: Option<NonNull<NamedCache<'f, F>>>,
+}
+
+// The list owns the caches, which are only shared by reference
+unsafe impl<'f, F: FrameAllocator + Sync> Send for NamedCacheList<'f, F> {}
+
+impl<'f, F: FrameAllocator> NamedCacheList<'f, F> {
+    fn iter(&self) -> impl Iterator<Item = &SlabCache<&'f F>> + '_ {
+        core::iter::successors(self.head, |node| unsafe { node.as_ref() }.next)
+            .map(|node| unsafe { &(*node.as_ptr()).cache })
+    }
 }
 
 impl<'f, F: FrameAllocator> KernelAllocator<'f, F> {
-    pub fn new(frame_allocator: &'f F) -> Self {
+    pub const fn new(frame_allocator: &'f F) -> Self {
         Self {
-            slab_32: Mutex::new(SlabCache::new(
+            frame_allocator,
+            slab_32: MagazineCache::new(SlabCache::new(
                 frame_allocator,
-                Layout::from_size_align(32, 32).unwrap(),
+                kmalloc_cache("kmalloc-32", 32),
             )),
-            slab_64: Mutex::new(SlabCache::new(
+            slab_64: MagazineCache::new(SlabCache::new(
                 frame_allocator,
-                Layout::from_size_align(64, 64).unwrap(),
+                kmalloc_cache("kmalloc-64", 64),
             )),
-            slab_128: Mutex::new(SlabCache::new(
+            slab_128: MagazineCache::new(SlabCache::new(
                 frame_allocator,
-                Layout::from_size_align(128, 128).unwrap(),
+                kmalloc_cache("kmalloc-128", 128),
             )),
-            slab_256: Mutex::new(SlabCache::new(
+            slab_256: MagazineCache::new(SlabCache::new(
                 frame_allocator,
-                Layout::from_size_align(256, 256).unwrap(),
+                kmalloc_cache("kmalloc-256", 256),
             )),
-            slab_512: Mutex::new(SlabCache::new(
+            slab_512: MagazineCache::new(SlabCache::new(
                 frame_allocator,
-                Layout::from_size_align(512, 512).unwrap(),
+                kmalloc_cache("kmalloc-512", 512),
             )),
-            slab_1k: Mutex::new(SlabCache::new(
+            slab_1k: MagazineCache::new(SlabCache::new(
                 frame_allocator,
-                Layout::from_size_align(1024, 1024).unwrap(),
-            )),
-            slab_2k: Mutex::new(SlabCache::new(
-                frame_allocator,
-                Layout::from_size_align(2048, 2048).unwrap(),
+                kmalloc_cache("kmalloc-1k", 1024),
             )),
+            named_caches: SpinLock::new(NamedCacheList { head: None }),
+        }
+    }
+
+    /// Creates a slab cache for a specific kind of object, which lives until
+    /// it is destroyed with `destroy_cache`.
+    pub fn create_cache(&self, options: SlabCacheOptions) -> Result<&SlabCache<&'f F>, AllocError> {
+        let node = self
+            .allocate(Layout::new::<NamedCache<'f, F>>())?
+            .cast::<NamedCache<'f, F>>();
+        let mut named_caches = self.named_caches.lock();
+        unsafe {
+            node.write(NamedCache {
+                cache: SlabCache::new(self.frame_allocator, options),
+                next: named_caches.head,
+            });
+        }
+        named_caches.head = Some(node);
+        Ok(unsafe { &(*node.as_ptr()).cache })
+    }
+
+    /// Fails if `cache` isn't a live cache of this allocator, for example
+    /// because it was already destroyed.
+    ///
+    /// # Safety
+    ///
+    /// There must be no objects allocated from `cache`.
+    // No subsystem that creates a cache goes away yet
+    #[allow(dead_code)]
+    pub unsafe fn destroy_cache(&self, cache: &SlabCache<&'f F>) -> Result<(), UnknownCacheError> {
+        let mut named_caches = self.named_caches.lock();
+        let mut link = &mut named_caches.head;
+        while let Some(mut node) = *link {
+            let node_ref = unsafe { node.as_mut() };
+            if core::ptr::eq(&node_ref.cache, cache) {
+                *link = node_ref.next;
+                unsafe {
+                    node_ref.cache.destroy();
+                    self.deallocate(node.cast(), Layout::new::<NamedCache<'f, F>>());
+                }
+                return Ok(());
+            }
+
+            link = &mut node_ref.next;
+        }
+
+        Err(UnknownCacheError)
+    }
+
+    /// Prints the stats of every cache, after returning the objects cached by
+    /// the current cpu to their slabs.
+    pub fn dump_stats(&self) {
+        sprintln!(
+            "{:<24} {:>6} {:>6} {:>8} {:>8} {:>8} {:>6} {:>6} {:>10}",
+            "cache",
+            "size",
+            "slab",
+            "in use",
+            "cached",
+            "peak",
+            "slabs",
+            "peak",
+            "bytes"
+        );
+        let builtin_caches = [
+            &self.slab_32,
+            &self.slab_64,
+            &self.slab_128,
+            &self.slab_256,
+            &self.slab_512,
+            &self.slab_1k,
+        ];
+        for cache in builtin_caches {
+            cache.flush();
+            print_cache_stats(cache.slab_cache().name, cache.stats());
+        }
+
+        for cache in self.named_caches.lock().iter() {
+            print_cache_stats(cache.name, cache.stats());
         }
     }
 }
 
-unsafe impl<'f, F: FrameAllocator> Allocator for KernelAllocator<'f, F> {
-    fn allocate(
-        &self,
-        layout: Layout,
-    ) -> Result<core::ptr::NonNull<[u8]>, core::alloc::AllocError> {
-        let size = layout.size();
+fn print_cache_stats(name: &str, stats: SlabCacheStats) {
+    sprintln!(
+        "{:<24} {:>6} {:>6} {:>8} {:>8} {:>8} {:>6} {:>6} {:>10}",
+        name,
+        stats.object_size,
+        stats.objects_per_slab,
+        stats.objects_in_use,
+        stats.objects_cached,
+        stats.peak_objects_in_use,
+        stats.slabs,
+        stats.peak_slabs,
+        stats.bytes_held()
+    );
+}
+
+impl<'f, F: FrameAllocator> KernelAllocator<'f, F> {
+    fn slab_cache(&self, layout: Layout) -> Option<&MagazineCache<&'f F>> {
+        // Objects in a slab are aligned to their size, so the alignment
+        // requirement can be folded into the size
+        let size = layout.size().max(layout.align());
         if size <= 32 {
-            self.slab_32.lock().allocate(layout)
+            Some(&self.slab_32)
         } else if size <= 64 {
-            self.slab_64.lock().allocate(layout)
+            Some(&self.slab_64)
         } else if size <= 128 {
-            self.slab_128.lock().allocate(layout)
+            Some(&self.slab_128)
         } else if size <= 256 {
-            self.slab_256.lock().allocate(layout)
+            Some(&self.slab_256)
         } else if size <= 512 {
-            self.slab_512.lock().allocate(layout)
+            Some(&self.slab_512)
         } else if size <= 1024 {
-            self.slab_1k.lock().allocate(layout)
-        } else if size <= 2048 {
-            self.slab_2k.lock().allocate(layout)
+            Some(&self.slab_1k)
         } else {
-            panic!("kernel allocator does not yet support 2k> allocations");
+            // A 2 KiB object is aligned past the slab header, so a single
+            // frame slab would only hold one of them. A whole frame wastes
+            // the same and is freed without searching for the slab
+            None
         }
     }
 
-    unsafe fn deallocate(&self, ptr: core::ptr::NonNull<u8>, layout: Layout) {
-        // TODO: free memory
+    // Allocations that don't fit in any slab get whole frames from the frame
+    // allocator, which are physically contiguous and therefore also
+    // contiguous in the offset mapping. Zero sized allocations only end up
+    // here when frame aligned, they still take a frame
+    fn allocate_frames(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        if layout.align() > FRAME_SIZE {
+            return Err(AllocError);
+        }
+
+        let num_frames = layout.size().max(1).div_ceil(FRAME_SIZE);
+        let frame = self
+            .frame_allocator
+            .allocate_frames(num_frames)
+            .map_err(|_| AllocError)?;
+        let mut page = FRAME_OFFSET_MAPPER.frame_to_page(frame);
+        let ptr = NonNull::new(page.as_ptr_mut::<u8>()).ok_or(AllocError)?;
+        Ok(NonNull::slice_from_raw_parts(ptr, num_frames * FRAME_SIZE))
+    }
+
+    fn deallocate_frames(&self, ptr: NonNull<u8>, layout: Layout) {
+        let num_frames = layout.size().max(1).div_ceil(FRAME_SIZE);
+        let frame = FRAME_OFFSET_MAPPER.page_to_frame(VirtAddr::new(ptr.as_ptr() as u64));
+        // If the frame allocator refuses the frames they are leaked, which is all we can do here
+        let _ = self.frame_allocator.deallocate_frames(frame, num_frames);
+    }
+}
+
+unsafe impl<'f, F: FrameAllocator> Allocator for KernelAllocator<'f, F> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        match self.slab_cache(layout) {
+            Some(slab_cache) => slab_cache.allocate(layout),
+            None => self.allocate_frames(layout),
+        }
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        match self.slab_cache(layout) {
+            Some(slab_cache) => unsafe { slab_cache.deallocate(ptr, layout) },
+            None => self.deallocate_frames(ptr, layout),
+        }
+    }
+}
+
+unsafe impl<'f, F: FrameAllocator> GlobalAlloc for KernelAllocator<'f, F> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.allocate(layout) {
+            Ok(ptr) => ptr.cast::<u8>().as_ptr(),
+            Err(_) => core::ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(ptr) = NonNull::new(ptr) {
+            unsafe { self.deallocate(ptr, layout) }
+        }
     }
 }
@@ -0,0 +1,185 @@
+use core::alloc::AllocError;
+use core::alloc::Allocator;
+use core::alloc::Layout;
+use core::cell::UnsafeCell;
+use core::ptr::NonNull;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+use common::frame::FrameAllocator;
+use x86_64::interrupts::without_interrupts;
+
+use crate::percpu;
+use crate::slub::SlabCache;
+use crate::slub::SlabCacheStats;
+use crate::slub::SLAB_DEBUG;
+use crate::smp::MAX_CPUS;
+
+const MAGAZINE_SIZE: usize = 32;
+// Refills and flushes move half a magazine, so that a cpu alternating between
+// allocating and freeing around the boundary doesn't hit the slab lists every time
+const BATCH_SIZE: usize = MAGAZINE_SIZE / 2;
+
+// Per cpu object caches in front of a shared slab cache. Each cpu allocates
+// from and frees to its own magazine without taking any lock, only when the
+// magazine runs empty or full is a batch of objects moved from or to the
+// slab lists, under the slab cache lock.
+#[derive(Debug)]
+pub struct MagazineCache<F: FrameAllocator> {
+    slab_cache: SlabCache<F>,
+    magazines: [UnsafeCell<Magazine>; MAX_CPUS],
+    // Fill level of each magazine, which other cpus can read for the stats
+    cached: [AtomicUsize; MAX_CPUS],
+}
+
+// A magazine is only ever touched by the cpu it belongs to, with interrupts
+// disabled so that an interrupt handler can't observe it half updated
+unsafe impl<F: FrameAllocator + Sync> Sync for MagazineCache<F> {}
+
+impl<F: FrameAllocator> MagazineCache<F> {
+    pub const fn new(slab_cache: SlabCache<F>) -> Self {
+        Self {
+            slab_cache,
+            magazines: [const { UnsafeCell::new(Magazine::new()) }; MAX_CPUS],
+            cached: [const { AtomicUsize::new(0) }; MAX_CPUS],
+        }
+    }
+
+    pub fn slab_cache(&self) -> &SlabCache<F> {
+        &self.slab_cache
+    }
+
+    /// The stats of the slab cache, with the objects in the magazines of all
+    /// cpus counted as cached instead of in use.
+    pub fn stats(&self) -> SlabCacheStats {
+        let mut stats = self.slab_cache.stats();
+        let cached = self
+            .cached
+            .iter()
+            .map(|cached| cached.load(Ordering::Relaxed))
+            .sum();
+        // The two are read at different times, a refill in between can make
+        // the magazines hold more than the slab cache had handed out
+        stats.objects_in_use = stats.objects_in_use.saturating_sub(cached);
+        stats.objects_cached = cached;
+        stats
+    }
+
+    /// Returns the objects in the magazine of the current cpu to the slabs.
+    pub fn flush(&self) {
+        without_interrupts(|| {
+            let Some(magazine) = self.magazine() else {
+                return;
+            };
+
+            unsafe { self.slab_cache.deallocate_bulk(magazine.take(magazine.len)) };
+            self.update_cached(magazine);
+        })
+    }
+
+    fn allocate_object(&self) -> Result<NonNull<u8>, AllocError> {
+        without_interrupts(|| {
+            // Objects sitting in a magazine look allocated to the slab
+            // debug checks, so bypass the magazines when debugging
+            let Some(magazine) = self.magazine().filter(|_| !SLAB_DEBUG) else {
+                return self.allocate_shared();
+            };
+
+            if magazine.len == 0 {
+                magazine.len = self
+                    .slab_cache
+                    .allocate_bulk(&mut magazine.objects[..BATCH_SIZE]);
+            }
+
+            let object = magazine.pop().ok_or(AllocError);
+            self.update_cached(magazine);
+            object
+        })
+    }
+
+    unsafe fn deallocate_object(&self, ptr: NonNull<u8>) {
+        without_interrupts(|| {
+            let Some(magazine) = self.magazine().filter(|_| !SLAB_DEBUG) else {
+                return unsafe { self.deallocate_shared(ptr) };
+            };
+
+            if magazine.len == MAGAZINE_SIZE {
+                unsafe { self.slab_cache.deallocate_bulk(magazine.take(BATCH_SIZE)) };
+            }
+
+            magazine.push(ptr);
+            self.update_cached(magazine);
+        })
+    }
+
+    fn allocate_shared(&self) -> Result<NonNull<u8>, AllocError> {
+        self.slab_cache
+            .allocate(self.slab_cache.object_layout)
+            .map(|ptr| ptr.cast())
+    }
+
+    unsafe fn deallocate_shared(&self, ptr: NonNull<u8>) {
+        unsafe {
+            self.slab_cache
+                .deallocate(ptr, self.slab_cache.object_layout)
+        }
+    }
+
+    fn update_cached(&self, magazine: &Magazine) {
+        self.cached[percpu::cpu_index()].store(magazine.len, Ordering::Relaxed);
+    }
+
+    /// Must be called with interrupts disabled, and the returned magazine
+    /// must not outlive that.
+    #[allow(clippy::mut_from_ref)]
+    fn magazine(&self) -> Option<&mut Magazine> {
+        let magazine = self.magazines.get(percpu::cpu_index())?;
+        Some(unsafe { &mut *magazine.get() })
+    }
+}
+
+unsafe impl<F: FrameAllocator> Allocator for MagazineCache<F> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.allocate_object()?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
+        unsafe { self.deallocate_object(ptr) }
+    }
+}
+
+#[derive(Debug)]
+struct Magazine {
+    objects: [NonNull<u8>; MAGAZINE_SIZE],
+    len: usize,
+}
+
+impl Magazine {
+    const fn new() -> Self {
+        Self {
+            objects: [NonNull::dangling(); MAGAZINE_SIZE],
+            len: 0,
+        }
+    }
+
+    fn pop(&mut self) -> Option<NonNull<u8>> {
+        if self.len == 0 {
+            return None;
+        }
+
+        self.len -= 1;
+        Some(self.objects[self.len])
+    }
+
+    fn push(&mut self, ptr: NonNull<u8>) {
+        self.objects[self.len] = ptr;
+        self.len += 1;
+    }
+
+    /// Removes the `count` most recently pushed objects.
+    fn take(&mut self, count: usize) -> &[NonNull<u8>] {
+        self.len -= count;
+        &self.objects[self.len..self.len + count]
+    }
+}
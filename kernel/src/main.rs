@@ -1,24 +1,45 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 #![feature(allocator_api)]
-#![feature(abi_x86_interrupt)]
+#![cfg_attr(not(test), feature(alloc_error_handler))]
 #![feature(format_args_nl)]
 #![feature(non_null_convenience)]
 // TODO: think about if this is necessary
 #![deny(unsafe_op_in_unsafe_fn)]
+// Host unit tests only build the modules, without the boot path using them
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+
+// Box, Vec and friends, backed by `KERNEL_ALLOCATOR`
+extern crate alloc;
 
 // mod bitmap;
+mod backtrace;
+mod exception;
 mod interrupt;
+mod ioapic;
 mod kalloc;
+mod magazine;
 mod msr;
+mod percpu;
+mod pit;
+mod sched;
 mod slub;
-mod spinlock;
+mod smp;
+mod time;
+mod tss;
+mod vmalloc;
+mod wait;
 
+use alloc::vec::Vec;
 use core::alloc::Allocator;
+use core::alloc::Layout;
 use core::panic::PanicInfo;
+use core::time::Duration;
 
+use acpi::tables::AcpiTable;
 use acpi::tables::DefinitionHeader;
 use acpi::tables::Fadt;
+use acpi::tables::Madt;
 use acpi::tables::Rsdp;
 use acpi2::aml::context::Context;
 use acpi2::aml::parser::Input;
@@ -33,13 +54,23 @@ use common::frame::FrameAllocator;
 use kalloc::KernelAllocator;
 use serial::SerialPort;
 use serial::COM1_BASE;
+use sync::TicketLock;
+use vmalloc::Vmalloc;
+use x86_64::control::Cr0;
 use x86_64::control::Cr3;
-use x86_64::gdt::GdtDesc;
-use x86_64::idt::IdtEntry;
+use x86_64::control::Cr4;
+use x86_64::cpuid::CpuFeatures;
+use x86_64::gdt;
+use x86_64::gdt::Descriptor;
+use x86_64::gdt::GlobalDescriptorTable;
+use x86_64::idt::InterruptDescriptorTable;
+use x86_64::interrupts;
+use x86_64::interrupts::without_interrupts;
 use x86_64::paging::MappedPageTable;
 use x86_64::paging::PageTable;
 use x86_64::paging::PageTableFrameMapper;
 use x86_64::paging::PageTableFrameOffsetMapper;
+use x86_64::tss::load_tss;
 
 #[macro_export]
 macro_rules! sprintln {
@@ -51,7 +82,11 @@ macro_rules! sprintln {
     }}
 }
 
+#[cfg(not(test))]
 const UPPER_HALF: u64 = 0xffff_8000_0000_0000;
+// Host unit tests hand out their own memory as frames
+#[cfg(test)]
+const UPPER_HALF: u64 = 0;
 pub const FRAME_OFFSET_MAPPER: PageTableFrameOffsetMapper =
     PageTableFrameOffsetMapper::new(UPPER_HALF);
 
@@ -64,14 +99,20 @@ fn ilog_ceil(base: usize, value: usize) -> usize {
     }
 }
 
+// Empty until the memory map has been read in _start
 #[derive(Debug)]
-struct Buddy(spinlock::Mutex<BuddyAllocator<5, 4096>>);
+struct Buddy(TicketLock<Option<BuddyAllocator<5, 4096>>>);
+
+// The buddy allocator is only ever touched through the lock
+unsafe impl Sync for Buddy {}
 
 impl FrameAllocator for Buddy {
     fn allocate_frames(&self, num_frames: usize) -> Result<PhysAddr, FrameAllocError> {
         let a = self
             .0
             .lock()
+            .as_mut()
+            .ok_or(FrameAllocError)?
             // TODO: f64log2 not in core, only std
             .allocate_order(ilog_ceil(2, num_frames) + 1)
             .map_err(|_| FrameAllocError)?;
@@ -81,37 +122,44 @@ impl FrameAllocator for Buddy {
     fn deallocate_frames(&self, frame: PhysAddr, num_frames: usize) -> Result<(), FrameAllocError> {
         self.0
             .lock()
+            .as_mut()
+            .ok_or(FrameAllocError)?
             .deallocate_order(frame.as_u64() as usize, ilog_ceil(2, num_frames) + 1);
         Ok(())
     }
 }
 
-#[derive(Debug)]
-#[repr(C, packed(2))]
-pub struct DescriptorTablePointer {
-    /// Size of the DT.
-    pub limit: u16,
-    /// Pointer to the memory region containing the DT.
-    pub base: u64,
-}
+static BUDDY: Buddy = Buddy(TicketLock::new(None));
 
-pub static mut LAPIC: msr::LApic = msr::LApic { base: 0 };
+#[cfg_attr(not(test), global_allocator)]
+static KERNEL_ALLOCATOR: KernelAllocator<'static, Buddy> = KernelAllocator::new(&BUDDY);
 
-#[no_mangle]
-pub extern "C" fn _start2() -> ! {
-    sprintln!("Cpu is starting...");
-    loop {
-        unsafe {
-            core::arch::asm!("hlt");
-        }
-    }
+static VMALLOC: Vmalloc<&Buddy> = Vmalloc::new(&BUDDY);
+
+per_cpu! {
+    pub static LAPIC: msr::LApic = msr::LApic { base: 0 };
 }
 
+// Timer interrupts per second, the resolution of `sched::sleep`
+const TICK_FREQUENCY: u32 = 100;
+
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn _start(info: &'static BootInfo) -> ! {
+    unsafe { percpu::init(0) };
+    sync::set_cpu_index(percpu::cpu_index);
     sprintln!("Kernel is starting...");
-
-    sprintln!("Address of _start2 is {:x}", _start2 as u64);
+    backtrace::init(&info.kernel_symbols);
+
+    let cpu_features = CpuFeatures::detect();
+    sprintln!(
+        "Cpu: {:?} family {:#x} model {:#x} stepping {}",
+        cpu_features.vendor,
+        cpu_features.family,
+        cpu_features.model,
+        cpu_features.stepping
+    );
+    init_cpu_protection();
 
     sprintln!("{:#x?}", info);
 
@@ -145,10 +193,8 @@ pub extern "C" fn _start(info: &'static BootInfo) -> ! {
     )
     .unwrap();
     buddy_allocator.add_regions(memory_regions).unwrap();
-    let buddy_allocator = Buddy(spinlock::Mutex::new(buddy_allocator));
-    let trampoline_frame = buddy_allocator.allocate_frame().unwrap().as_u64();
-
-    let kalloc = KernelAllocator::new(&buddy_allocator);
+    *BUDDY.0.lock() = Some(buddy_allocator);
+    let trampoline_frame = BUDDY.allocate_frame().unwrap();
 
     let page_table = FRAME_OFFSET_MAPPER
         .frame_to_page(PhysAddr::new(Cr3::read().pba_pml4))
@@ -160,35 +206,54 @@ pub extern "C" fn _start(info: &'static BootInfo) -> ! {
         let current_pages = (info.kernel.stack_start - info.kernel.stack_end + 4095) / 4096;
         let target_pages = 64.max(current_pages);
         for i in 0..target_pages - current_pages {
-            let frame = buddy_allocator.allocate_frame().unwrap();
+            let frame = BUDDY.allocate_frame().unwrap();
             mapped_page_table
                 .map(
                     VirtAddr::new(info.kernel.stack_end - (i + 1) * 4096),
                     frame,
-                    &buddy_allocator,
+                    &BUDDY,
                     true,
                 )
                 .unwrap();
         }
     };
 
-    let allocated_frames = buddy_allocator.0.lock().allocated_bytes.div_ceil(4096);
-    sprintln!("Allocated frames: {:?}(KiB)", allocated_frames);
+    sprintln!("Setting up kernel virtual memory...");
+    VMALLOC.init(mapped_page_table);
+    {
+        let area = VMALLOC.allocate(16, 1, true).unwrap();
+        let buffer =
+            unsafe { core::slice::from_raw_parts_mut(area.as_ptr_mut::<u8>(), area.size()) };
+        buffer.fill(0xab);
+        sprintln!(
+            "Mapped {} pages at {:#x}..{:#x}",
+            area.pages(),
+            area.start().as_u64(),
+            area.end().as_u64()
+        );
+        unsafe { VMALLOC.deallocate(area) };
+    }
 
-    let gdt = {
-        let frame = buddy_allocator.allocate_frame().unwrap();
-        let mut page = FRAME_OFFSET_MAPPER.frame_to_page(frame);
-        page.as_slice_mut::<u64>(4096 / core::mem::size_of::<u64>())
-    };
+    sprintln!("Setting up scheduler...");
+    sched::init();
+
+    let allocated_frames = BUDDY
+        .0
+        .lock()
+        .as_ref()
+        .unwrap()
+        .allocated_bytes
+        .div_ceil(4096);
+    sprintln!("Allocated frames: {:?}(KiB)", allocated_frames);
 
     let idt = {
-        let frame = buddy_allocator.allocate_frame().unwrap();
+        let frame = BUDDY.allocate_frame().unwrap();
         let mut page = FRAME_OFFSET_MAPPER.frame_to_page(frame);
-        page.as_slice_mut::<IdtEntry>(4096 / core::mem::size_of::<IdtEntry>())
+        page.as_ref_mut::<InterruptDescriptorTable>()
     };
 
     sprintln!("Setting up GDT...");
-    init_gdt(gdt);
+    init_gdt();
 
     sprintln!("Setting up IDT...");
     interrupt::init(idt);
@@ -199,93 +264,49 @@ pub extern "C" fn _start(info: &'static BootInfo) -> ! {
     }
 
     sprintln!("Setting up Local APIC for timer interrupts...");
-    unsafe {
-        LAPIC = {
-            let lapic = msr::LApic::current();
-            let phys_addr = PhysAddr::new(lapic.base);
-            let virt_addr = FRAME_OFFSET_MAPPER.frame_to_page(phys_addr);
-            msr::LApic {
-                base: virt_addr.as_u64(),
-            }
-        }
-    };
-    unsafe {
-        // This line enables the lapic (i think), so not specific to timers
-        LAPIC.write_spurious_interrupt_vector((1 << 8) | 0x99);
-        let timer_enabled = false;
-        if timer_enabled {
-            LAPIC.write_divide_configuration(0b1010);
-            LAPIC.write_timer_lvt((1 << 17) | 0x20);
-        }
-    }
-
-    {
-        // TODO: get these from MADT
-        // Define I/O APIC registers addresses
-        const IOAPIC_REGSEL: u64 = 0xFEC00000;
-        const IOAPIC_REGWIN: u64 = 0xFEC00010;
-
-        // Function to write to an I/O APIC register
-        fn ioapic_write(reg: u32, value: u32) {
-            unsafe {
-                (IOAPIC_REGSEL as *mut u32).write(reg);
-                (IOAPIC_REGWIN as *mut u32).write(value);
-            }
-        }
-
-        // Route PS/2 interrupt (IRQ 0x12) to vector 0x21
-        let ioapic_irq_low: u32 = 0x21 | (1 << 15); // Set the destination CPU (in this case, CPU 0)
-        let ioapic_irq_high: u32 = 0; // Set the delivery mode and other flags to default (0)
-
-        // Configure the I/O APIC entry for IRQ 0x12
-        ioapic_write(0x12, ioapic_irq_low);
-        ioapic_write(0x13, ioapic_irq_high);
-
-        sprintln!("PS/2 interrupt (IRQ 0x1) routed to vector 0x21 successfully");
-    }
-
-    unsafe {
-        LAPIC.write_icr_low(0x000C4500);
-        if trampoline_frame >= 0x100000 {
-            panic!(
-                "trampoline must be loaded in a frame below 1MB {:x?}",
-                trampoline_frame
-            );
-        }
-
-        let trampoline_code = include_bytes!("../trampoline.bin");
-
-        let slice = core::slice::from_raw_parts_mut(trampoline_frame as *mut u8, 4096);
-        for (i, b) in trampoline_code.iter().enumerate() {
-            slice[i] = *b;
-        }
-
-        let stack = buddy_allocator.allocate_frame().unwrap();
-        sprintln!("trampoline segment {:#x?}", trampoline_frame / 4096);
-        sprintln!(
-            "stack addr {:#x?}",
-            FRAME_OFFSET_MAPPER.frame_to_page(stack)
-        );
-
-        const SEGMENT_BASE: usize = 0x800;
-        const STACK_ROOT: usize = 0x808;
-        const KERNEL_START: usize = 0x810;
-        const PML4_ADDR: usize = 0x818;
-        slice[SEGMENT_BASE..SEGMENT_BASE + 8].copy_from_slice(&trampoline_frame.to_le_bytes());
-        slice[STACK_ROOT..STACK_ROOT + 8].copy_from_slice(
-            &FRAME_OFFSET_MAPPER
-                .frame_to_page(stack.add(0x1000))
-                .as_u64()
-                .to_le_bytes(),
-        );
-        slice[KERNEL_START..KERNEL_START + 8].copy_from_slice(&(_start2 as u64).to_le_bytes());
-        slice[PML4_ADDR..PML4_ADDR + 8].copy_from_slice(&Cr3::read().pba_pml4.to_le_bytes());
-        sprintln!("{:x?}", &slice[0x800..0x820]);
+    init_lapic();
+    time::init(find_acpi_table::<Fadt>(info.rsdp as u64), &cpu_features);
+    let timer_vector = interrupt::allocate_vector().unwrap();
+    interrupt::register_closure(timer_vector, &|| {
+        sched::tick();
+        true
+    })
+    .unwrap();
+    time::start_tick(timer_vector, TICK_FREQUENCY);
 
-        LAPIC.write_icr_low(0x000C4600 | (trampoline_frame as u32 / 4096));
+    let madt = find_acpi_table::<Madt>(info.rsdp as u64).unwrap();
+    for entry in madt.entries() {
+        sprintln!("{:x?}", entry);
     }
 
-    loop {}
+    sprintln!("Setting up I/O apics...");
+    ioapic::init(madt);
+    let bsp_apic_id = LAPIC.with(|lapic| lapic.read_id());
+    let keyboard = ioapic::isa_irq(1).unwrap();
+    let keyboard_vector = interrupt::allocate_vector().unwrap();
+    interrupt::register(keyboard_vector, interrupt::keyboard, 0).unwrap();
+    ioapic::route_isa_irq(1, keyboard_vector, bsp_apic_id).unwrap();
+    ioapic::unmask(keyboard.gsi).unwrap();
+    sprintln!(
+        "PS/2 keyboard (gsi {}) routed to vector {:#x}",
+        keyboard.gsi,
+        keyboard_vector
+    );
+
+    // Everything the BSP takes interrupts for is set up now
+    interrupts::enable();
+
+    sprintln!("Starting application processors...");
+    smp::init_shootdown();
+    let apic_ids: Vec<u8> = madt
+        .processors()
+        .filter(|processor| processor.enabled)
+        // The xapic can only address 8 bit apic ids
+        .filter_map(|processor| u8::try_from(processor.apic_id).ok())
+        .collect();
+    smp::start_aps(&apic_ids, trampoline_frame);
+
+    // Still on the large boot stack, the AML parser recurses deeply
     let rsdp_addr = FRAME_OFFSET_MAPPER
         .frame_to_page(PhysAddr::new(info.rsdp as u64))
         .as_u64();
@@ -307,113 +328,166 @@ pub extern "C" fn _start(info: &'static BootInfo) -> ! {
                 .frame_to_page(PhysAddr::new(fadt.dsdt as u64))
                 .as_u64();
             sprintln!("Reading dsdt...");
-            print_dsdt(dsdt_addr, &kalloc);
+            print_dsdt(dsdt_addr, &KERNEL_ALLOCATOR);
         }
+    }
 
-        if sig == "APIC" {
-            let mut offset: usize = 44;
-            while offset < header.length as usize {
-                let ptr = unsafe { table_ptr.cast::<u8>().add(offset) };
-                let ty = unsafe { ptr.read() };
-                let len = unsafe { ptr.add(1).read() };
-                match ty {
+    #[cfg(feature = "self-test")]
+    sched::spawn(self_test).unwrap();
+
+    // Threads that haven't finished their rounds yet
+    static RUNNING: wait::Mutex<usize> = wait::Mutex::new(2);
+    static FINISHED: wait::Condvar = wait::Condvar::new();
+    for i in 0..2 {
+        sched::spawn(move || {
+            for round in 0..3 {
+                sprintln!("Thread {:?} ({}): round {}", sched::current_id(), i, round);
+                match i {
                     0 => {
-                        let uid = unsafe { ptr.add(2).read() };
-                        let apic_id = unsafe { ptr.add(3).read() };
-                        let flags = unsafe { ptr.add(4).cast::<u32>().read_unaligned() };
-                        sprintln!(
-                            "Processor local apic => uid: {}, apic id: {}, flags: {:#b}",
-                            uid,
-                            apic_id,
-                            flags
-                        );
-                    }
-                    1 => {
-                        let apic_id = unsafe { ptr.add(2).read() };
-                        let io_apic_addr = unsafe { ptr.add(4).cast::<u32>().read_unaligned() };
-                        let global_system_interrupt_base =
-                            unsafe { ptr.add(8).cast::<u32>().read_unaligned() };
-                        sprintln!(
-                            "I/O apic => apic id: {}, ip apic addr: {:#x}, global system interrupt base: {}",
-                            apic_id,
-                            io_apic_addr,
-                            global_system_interrupt_base
-                        );
-                    }
-                    2 => {
-                        let bus = unsafe { ptr.add(2).read() };
-                        let source = unsafe { ptr.add(3).read() };
-                        let global_system_interrupt =
-                            unsafe { ptr.add(4).cast::<u32>().read_unaligned() };
-                        let flags = unsafe { ptr.add(8).cast::<u16>().read_unaligned() };
+                        let start = time::Instant::now();
+                        sched::sleep(Duration::from_millis(100));
                         sprintln!(
-                            "Interrupt source override => bus: {}, source: {}, global system interrupt: {}, flags: {:#b}",
-                            bus,
-                            source,
-                            global_system_interrupt,
-                            flags
+                            "Thread {:?} slept {:?}",
+                            sched::current_id(),
+                            start.elapsed()
                         );
                     }
-                    _ => (),
+                    _ => sched::yield_now(),
                 }
-                offset += len as usize;
             }
-        }
+            *RUNNING.lock() -= 1;
+            FINISHED.notify_all();
+        })
+        .unwrap();
     }
+    sched::spawn(|| {
+        drop(FINISHED.wait_while(RUNNING.lock(), |running| *running > 0));
+        interrupt::print_statistics();
+        KERNEL_ALLOCATOR.dump_stats();
+    })
+    .unwrap();
 
-    sprintln!("Entering halt loop...");
-    loop {
-        unsafe { core::arch::asm!("hlt") };
-    }
+    sched::spawn(interrupt::keyboard_reader).unwrap();
+
+    // The boot thread is done, the spawned threads carry on from here
+    sched::exit();
 }
 
-fn init_gdt(gdt: &mut [u64]) {
-    // null segment
-    gdt[0] = 0;
-    // kernel code segment
-    // flags(0x2) = [long mode], access byte(0x9a) = [present, desc type = code/data segment, executable, rw]
-    gdt[1] = 0x0020_9a00_0000_0000;
-    // kernel data segment
-    // flags(0x2) = [long mode], access byte(0x92) = [present, desc type = code/data segment, rw]
-    gdt[2] = 0x0020_9200_0000_0000;
+// Checks what the host unit tests can't: that a self ipi reaches its handler,
+// which wakes this thread, and that threads hand items to each other through a
+// condition variable. Runs as a thread, since waiting needs one
+#[cfg(feature = "self-test")]
+fn self_test() {
+    sprintln!("Running self test...");
+    static FIRED: wait::Event = wait::Event::new();
+    fn handler(_: usize) -> bool {
+        FIRED.set();
+        true
+    }
 
-    unsafe {
-        let ptr = DescriptorTablePointer {
-            limit: (gdt.len() * core::mem::size_of::<GdtDesc>() - 1) as u16,
-            base: gdt.as_ptr() as _,
-        };
-        core::arch::asm!("cli");
-        core::arch::asm!("lgdt [{}]", in(reg) &ptr);
-        core::arch::asm!("sti");
+    let vector = interrupt::allocate_vector().unwrap();
+    let id = interrupt::register(vector, handler, 0).unwrap();
+    smp::send_self_ipi(vector);
+    FIRED.wait();
+    interrupt::unregister(id);
+    interrupt::free_vector(vector).unwrap();
+
+    static ITEMS: wait::Mutex<usize> = wait::Mutex::new(0);
+    static ADDED: wait::Condvar = wait::Condvar::new();
+    static CONSUMED: wait::Event = wait::Event::new();
+    const COUNT: usize = 3;
+    sched::spawn(|| {
+        for _ in 0..COUNT {
+            let mut items = ADDED.wait_while(ITEMS.lock(), |items| *items == 0);
+            *items -= 1;
+        }
+        CONSUMED.set();
+    })
+    .unwrap();
+
+    for _ in 0..COUNT {
+        *ITEMS.lock() += 1;
+        ADDED.notify_one();
+        sched::yield_now();
     }
+    CONSUMED.wait();
+    assert_eq!(*ITEMS.lock(), 0);
+    sprintln!("Self test ok!");
+}
 
-    reload_segments();
+fn find_acpi_table<T: AcpiTable>(rsdp_addr: u64) -> Option<&'static T> {
+    let rsdp_addr = FRAME_OFFSET_MAPPER
+        .frame_to_page(PhysAddr::new(rsdp_addr))
+        .as_u64();
+    let rsdp = unsafe { Rsdp::from_addr(rsdp_addr) };
+    rsdp.table_ptrs()
+        .map(|table_ptr| {
+            FRAME_OFFSET_MAPPER
+                .frame_to_page(PhysAddr::new(table_ptr as u64))
+                .as_ptr::<DefinitionHeader>()
+        })
+        .find(|table_ptr| unsafe { table_ptr.read() }.signature == T::SIGNATURE)
+        .map(|table_ptr| unsafe { &*table_ptr.cast::<T>() })
 }
 
-// TODO: move to x86_64 crate
-fn reload_segments() {
+/// Turns on the protections the cpu supports: write protection of read only
+/// pages in supervisor mode, SMEP, SMAP and UMIP. Has to run on every cpu.
+fn init_cpu_protection() {
+    let cpu_features = CpuFeatures::detect();
     unsafe {
-        core::arch::asm!(
-                // push the segment selector, index 1 of the gdt
-                "push 0x8",
-                // load and push the address of the "2" label
-                "lea {tmp}, [rip + 2f]",
-                "push {tmp}",
-                // far return, popping the return address and the new CS value from the stack
-                "retfq",
-                "2:",
-                // set the rest of the segment registers to the data segment in the gdt
-                "mov ax, 0x10",
-                "mov ds, ax",
-                "mov es, ax",
-                "mov fs, ax",
-                "mov gs, ax",
-                "mov ss, ax",
-                tmp = lateout(reg) _,
-        );
+        Cr0::update(|cr0| {
+            cr0.set_write_protect(true);
+        });
+        Cr4::update(|cr4| {
+            cr4.set_smep(cpu_features.smep)
+                .set_smap(cpu_features.smap)
+                .set_umip(cpu_features.umip);
+        });
     }
 }
 
+fn init_lapic() {
+    assert!(CpuFeatures::detect().apic, "The local APIC is required");
+    LAPIC.with(|lapic| {
+        let phys_addr = PhysAddr::new(msr::LApic::current().base);
+        lapic.base = FRAME_OFFSET_MAPPER.frame_to_page(phys_addr).as_u64();
+        // This line enables the lapic (i think), so not specific to timers
+        lapic.write_spurious_interrupt_vector((1 << 8) | interrupt::SPURIOUS_VECTOR as u32);
+    });
+}
+
+per_cpu! {
+    static GDT: GlobalDescriptorTable = GlobalDescriptorTable::new();
+}
+
+fn init_gdt() {
+    let (gdt, selectors) = GDT.with(|gdt| {
+        let kernel_code = gdt.append(Descriptor::kernel_code_segment());
+        let kernel_data = gdt.append(Descriptor::kernel_data_segment());
+        // User data comes before user code, the order sysret expects
+        gdt.append(Descriptor::user_data_segment());
+        gdt.append(Descriptor::user_code_segment());
+        let tss = gdt.append(Descriptor::tss_segment(tss::init()));
+        (
+            gdt as *const GlobalDescriptorTable,
+            (kernel_code, kernel_data, tss),
+        )
+    });
+    let (kernel_code, kernel_data, tss) = selectors;
+
+    // Every cpu has its own table, which is never touched again
+    let gdt = unsafe { &*gdt };
+    without_interrupts(|| {
+        gdt.load();
+        gdt::set_cs(kernel_code);
+        // Not fs and gs, loading them would clear the per cpu gs base
+        gdt::load_ss(kernel_data);
+        gdt::load_ds(kernel_data);
+        gdt::load_es(kernel_data);
+        unsafe { load_tss(tss.0) };
+    });
+}
+
 fn print_dsdt<A: Allocator>(dsdt_addr: u64, alloc: &A) {
     let ptr = dsdt_addr as *const DefinitionHeader;
     let hdr = unsafe { ptr.read() };
@@ -431,9 +505,21 @@ fn print_dsdt<A: Allocator>(dsdt_addr: u64, alloc: &A) {
     }
 }
 
+/// This function is called when an allocation through the global allocator fails.
+#[cfg(not(test))]
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    sprintln!("Allocation failed: {:?}", layout);
+    loop {
+        unsafe { core::arch::asm!("hlt") };
+    }
+}
+
 /// This function is called on panic.
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     sprintln!("{}", info);
+    let _ = backtrace::print_current(&mut SerialPort::new(COM1_BASE));
     loop {}
 }
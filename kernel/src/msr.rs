@@ -1,47 +1,11 @@
-pub unsafe fn rdmsr(msr: u32) -> u64 {
-    let low: u32;
-    let high: u32;
-    unsafe {
-        core::arch::asm!("rdmsr", out("eax") low, out("edx") high, in("ecx") msr);
-    }
-    ((high as u64) << 32) | (low as u64)
-}
-
-pub struct LApicInfo(u64);
-
-impl core::fmt::Debug for LApicInfo {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        f.debug_struct("LApicInfo")
-            .field("bsp", &self.bsp())
-            .field("global_enable", &self.global_enable())
-            .field("base_addr", &self.base_addr())
-            .finish()
-    }
-}
-
-impl LApicInfo {
-    pub fn bsp(&self) -> bool {
-        self.0 & (1 << 8) != 0
-    }
-
-    pub fn global_enable(&self) -> bool {
-        self.0 & (1 << 11) != 0
-    }
-
-    pub fn base_addr(&self) -> u64 {
-        self.0 & 0x0000_000f_ffff_f000
-    }
-}
-
-pub fn lapic_info() -> LApicInfo {
-    LApicInfo(unsafe { rdmsr(0x1b) })
-}
+use x86_64::msr::ApicBase;
 
 pub struct LApic {
     pub base: u64,
 }
 
 impl LApic {
+    const ID: u64 = 0x0020;
     const EOI: u64 = 0x00b0;
     const SPURIOUS_VECTOR_INTERRUPT: u64 = 0x00f0;
     const ICR_LOW: u64 = 0x300;
@@ -53,10 +17,14 @@ impl LApic {
 
     pub fn current() -> Self {
         Self {
-            base: lapic_info().base_addr(),
+            base: ApicBase::read().base_addr().as_u64(),
         }
     }
 
+    pub fn read_id(&self) -> u8 {
+        (self.read(Self::ID) >> 24) as u8
+    }
+
     pub fn write_eoi(&self) {
         self.write(Self::EOI, 0)
     }
@@ -97,6 +65,10 @@ impl LApic {
         self.read(Self::CURRENT_COUNT)
     }
 
+    pub fn read_icr_low(&self) -> u32 {
+        self.read(Self::ICR_LOW)
+    }
+
     pub fn write_icr_low(&self, value: u32) {
         self.write(Self::ICR_LOW, value)
     }
@@ -0,0 +1,124 @@
+use core::cell::UnsafeCell;
+
+use x86_64::interrupts::without_interrupts;
+use x86_64::msr::GsBase;
+use x86_64::msr::KernelGsBase;
+
+use crate::smp::MAX_CPUS;
+
+// Each cpu has IA32_GS_BASE pointing at its `CpuLocal` while running kernel
+// code. IA32_KERNEL_GS_BASE holds the user mode value, entry code coming from
+// user mode has to `swapgs` before touching per cpu data, and again before
+// returning, see `swapgs_if_user!`.
+static CPU_LOCALS: [CpuLocal; MAX_CPUS] = {
+    let mut locals = [const { CpuLocal::new() }; MAX_CPUS];
+    let mut cpu = 0;
+    while cpu < MAX_CPUS {
+        locals[cpu].cpu_index = cpu;
+        cpu += 1;
+    }
+    locals
+};
+
+#[derive(Debug)]
+#[repr(C)]
+struct CpuLocal {
+    // Read through gs:[0] to find the block itself
+    self_ptr: UnsafeCell<*const CpuLocal>,
+    // Read through gs:[8]
+    cpu_index: usize,
+}
+
+unsafe impl Sync for CpuLocal {}
+
+impl CpuLocal {
+    const fn new() -> Self {
+        Self {
+            self_ptr: UnsafeCell::new(core::ptr::null()),
+            cpu_index: 0,
+        }
+    }
+}
+
+/// Points the GS base of the current cpu at its per cpu block. Has to run
+/// before anything on the cpu touches per cpu data, which includes the
+/// kernel allocator.
+///
+/// # Safety
+///
+/// `cpu` must be unique to the calling cpu.
+pub unsafe fn init(cpu: usize) {
+    let local = &CPU_LOCALS[cpu];
+    unsafe {
+        *local.self_ptr.get() = local;
+        GsBase::write(local as *const CpuLocal as u64);
+        KernelGsBase::write(0);
+    }
+}
+
+/// Index of the current cpu, 0 for the bootstrap processor.
+pub fn cpu_index() -> usize {
+    let cpu_index: usize;
+    unsafe {
+        core::arch::asm!(
+            "mov {}, gs:[8]",
+            out(reg) cpu_index,
+            options(nostack, readonly, preserves_flags),
+        );
+    }
+    cpu_index
+}
+
+/// Assembly for interrupt and exception stubs (AT&T syntax) that swaps the GS
+/// base if the interrupted code ran in user mode. Goes at the very start of the
+/// stub and again right before `iretq`. `$cs_offset` is the offset of the saved
+/// CS from `%rsp` at that point.
+#[macro_export]
+macro_rules! swapgs_if_user {
+    ($cs_offset:literal) => {
+        concat!(
+            "testb $3, ",
+            $cs_offset,
+            "(%rsp)\n",
+            "jz 2f\n",
+            "swapgs\n",
+            "2:"
+        )
+    };
+}
+
+/// A variable with a separate instance for every cpu, declared through
+/// `per_cpu!`.
+pub struct PerCpu<T> {
+    values: [UnsafeCell<T>; MAX_CPUS],
+}
+
+// Every cpu only ever touches its own instance
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    pub const fn new(values: [UnsafeCell<T>; MAX_CPUS]) -> Self {
+        Self { values }
+    }
+
+    /// Runs `f` on the instance of the current cpu. Interrupts are disabled
+    /// meanwhile, so that the thread can't be moved to another cpu or have an
+    /// interrupt handler access the same instance. `f` must not access the
+    /// same variable again.
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        without_interrupts(|| f(unsafe { &mut *self.values[cpu_index()].get() }))
+    }
+}
+
+/// Declares per cpu variables, each cpu starts out with its own copy of the
+/// initial value.
+#[macro_export]
+macro_rules! per_cpu {
+    ($($vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $vis static $name: $crate::percpu::PerCpu<$ty> = $crate::percpu::PerCpu::new(
+                [const { core::cell::UnsafeCell::new($init) }; $crate::smp::MAX_CPUS],
+            );
+        )*
+    };
+}
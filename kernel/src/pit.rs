@@ -0,0 +1,44 @@
+use x86_64::port::Port;
+use x86_64::port::PortWriteOnly;
+
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+// Bit 0 gates channel 2, bit 1 connects it to the speaker and bit 5 reflects
+// the output of channel 2
+const SPEAKER_CONTROL: u16 = 0x61;
+
+// Channel 2, low then high byte, mode 0 (interrupt on terminal count)
+const CHANNEL_2_ONE_SHOT: u8 = 0b1011_0000;
+
+/// Busy waits for at least `us` microseconds using channel 2 of the PIT, which
+/// needs no interrupts and works before any other timer is calibrated.
+pub fn delay_us(us: u64) {
+    let mut ticks = (us * PIT_FREQUENCY).div_ceil(1_000_000);
+    while ticks > 0 {
+        let count = ticks.min(0xffff);
+        one_shot(count as u16);
+        ticks -= count;
+    }
+}
+
+fn one_shot(count: u16) {
+    let mut speaker_control = Port::<u8>::new(SPEAKER_CONTROL);
+    let mut command = PortWriteOnly::<u8>::new(COMMAND);
+    let mut channel_2_data = Port::<u8>::new(CHANNEL_2_DATA);
+    unsafe {
+        let control = speaker_control.read() & !0b11;
+        speaker_control.write(control);
+        command.write(CHANNEL_2_ONE_SHOT);
+        channel_2_data.write(count as u8);
+        channel_2_data.write((count >> 8) as u8);
+        // Raising the gate starts the countdown, the output goes high when it
+        // reaches zero
+        speaker_control.write(control | 1);
+        while speaker_control.read() & (1 << 5) == 0 {
+            core::hint::spin_loop();
+        }
+        speaker_control.write(control);
+    }
+}
@@ -0,0 +1,540 @@
+use core::alloc::Allocator;
+use core::alloc::Layout;
+use core::ptr::NonNull;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+use sync::SpinLock;
+use x86_64::interrupts;
+use x86_64::interrupts::without_interrupts;
+
+use crate::percpu;
+use crate::slub::SlabCache;
+use crate::slub::SlabCacheOptions;
+use crate::time::Instant;
+use crate::vmalloc::VirtualArea;
+use crate::Buddy;
+use crate::KERNEL_ALLOCATOR;
+use crate::VMALLOC;
+
+const STACK_PAGES: usize = 8;
+const STACK_GUARD_PAGES: usize = 1;
+// How long a thread runs before the next ready one gets its turn, rounded up
+// to whole timer ticks
+const TIME_SLICE: Duration = Duration::from_millis(20);
+
+// Round robin scheduling over a single run queue. The timer interrupt calls
+// `tick`, which wakes sleepers and asks for the running thread to be
+// preempted once the interrupt is acknowledged, see `preempt`. Threads can
+// also give up the cpu themselves through `yield_now`, `sleep`, `exit` and by
+// blocking on a `WaitQueue`.
+//
+// Only the bootstrap processor runs threads. There is one run queue and no
+// per cpu state for the current thread, so the application processors stay
+// out of the scheduler: they take interrupts and halt in between.
+static SCHEDULER: SpinLock<Option<Scheduler>> = SpinLock::new(None);
+
+// Set by `tick` when the time slice of the current thread is used up
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ThreadId(usize);
+
+#[derive(Debug)]
+pub enum SpawnError {
+    NotInitialized,
+    OutOfMemory,
+    ClosureTooLarge,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ThreadState {
+    Ready,
+    Running,
+    Sleeping { wake_at: Instant },
+    // In a `WaitQueue`
+    Blocked,
+    Dead,
+}
+
+#[derive(Debug)]
+struct Thread {
+    id: ThreadId,
+    state: ThreadState,
+    // Saved stack pointer while the thread isn't running, the callee saved
+    // registers are on the stack, see `switch_context`
+    rsp: u64,
+    // The boot thread runs on the stack set up by the bootloader
+    stack: Option<VirtualArea>,
+    next: Option<NonNull<Thread>>,
+}
+
+struct Scheduler {
+    current: NonNull<Thread>,
+    idle: NonNull<Thread>,
+    ready: ThreadQueue,
+    sleeping: ThreadQueue,
+    // Threads that have exited but whose stacks can't be freed from the
+    // thread itself, they are released by `reap`
+    dead: ThreadQueue,
+    thread_cache: &'static SlabCache<&'static Buddy>,
+    next_id: usize,
+    // When the current thread was switched to, set by the first `tick` after
+    // the switch
+    slice_start: Option<Instant>,
+}
+
+unsafe impl Send for Scheduler {}
+
+/// Turns the calling code into the first thread and sets up the idle thread.
+pub fn init() {
+    let thread_cache = KERNEL_ALLOCATOR
+        .create_cache(SlabCacheOptions::new("thread", Layout::new::<Thread>()))
+        .unwrap();
+    let boot = allocate_thread(thread_cache, ThreadId(0), 0, None).unwrap();
+    unsafe { (*boot.as_ptr()).state = ThreadState::Running };
+    let idle = create_thread(thread_cache, ThreadId(1), idle).unwrap();
+    unsafe { (*idle.as_ptr()).state = ThreadState::Running };
+
+    without_interrupts(|| {
+        *SCHEDULER.lock() = Some(Scheduler {
+            current: boot,
+            idle,
+            ready: ThreadQueue::new(),
+            sleeping: ThreadQueue::new(),
+            dead: ThreadQueue::new(),
+            thread_cache,
+            next_id: 2,
+            slice_start: None,
+        })
+    });
+}
+
+/// Starts a new kernel thread running `f`, it exits when `f` returns.
+pub fn spawn<F: FnOnce() + Send + 'static>(f: F) -> Result<ThreadId, SpawnError> {
+    reap();
+
+    let (thread_cache, id) = without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().ok_or(SpawnError::NotInitialized)?;
+        let id = ThreadId(scheduler.next_id);
+        scheduler.next_id += 1;
+        Ok((scheduler.thread_cache, id))
+    })?;
+
+    let thread = create_thread(thread_cache, id, f)?;
+    without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        unsafe { scheduler.as_mut().unwrap().ready.push(thread) };
+    });
+
+    Ok(id)
+}
+
+pub fn current_id() -> ThreadId {
+    without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        unsafe { scheduler.as_ref().unwrap().current.as_ref().id }
+    })
+}
+
+/// Gives up the rest of the time slice to the next ready thread.
+pub fn yield_now() {
+    without_interrupts(schedule);
+}
+
+/// Blocks the current thread for at least `duration`, rounded up to the
+/// next timer tick.
+pub fn sleep(duration: Duration) {
+    let wake_at = Instant::now() + duration;
+    without_interrupts(|| {
+        {
+            let mut scheduler = SCHEDULER.lock();
+            let scheduler = scheduler.as_mut().unwrap();
+            let mut current = scheduler.current;
+            unsafe {
+                current.as_mut().state = ThreadState::Sleeping { wake_at };
+                scheduler.sleeping.push(current);
+            }
+        }
+
+        schedule();
+    });
+}
+
+/// Ends the current thread.
+pub fn exit() -> ! {
+    interrupts::disable();
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().unwrap();
+        let mut current = scheduler.current;
+        unsafe {
+            current.as_mut().state = ThreadState::Dead;
+            scheduler.dead.push(current);
+        }
+    }
+
+    schedule();
+    unreachable!("dead thread was scheduled");
+}
+
+/// Called from the timer interrupt, wakes sleeping threads whose time is up
+/// and ends the time slice of the current thread once it is used up.
+pub fn tick() {
+    let mut scheduler = SCHEDULER.lock();
+    let Some(scheduler_ref) = scheduler.as_mut() else {
+        return;
+    };
+
+    let now = Instant::now();
+    let mut sleeping = core::mem::replace(&mut scheduler_ref.sleeping, ThreadQueue::new());
+    while let Some(mut thread) = unsafe { sleeping.pop() } {
+        let thread_ref = unsafe { thread.as_mut() };
+        match thread_ref.state {
+            ThreadState::Sleeping { wake_at } if wake_at <= now => {
+                thread_ref.state = ThreadState::Ready;
+                unsafe { scheduler_ref.ready.push(thread) };
+            }
+            _ => unsafe { scheduler_ref.sleeping.push(thread) },
+        }
+    }
+
+    let slice_start = *scheduler_ref.slice_start.get_or_insert(now);
+    if now - slice_start >= TIME_SLICE {
+        NEED_RESCHED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Switches threads if `tick` asked for it. Called by the interrupt dispatcher
+/// after the end of interrupt has been signalled, the next thread might not
+/// return through the interrupted code for a while.
+pub fn preempt() {
+    // Application processors never run threads, see the top of this file
+    if percpu::cpu_index() != 0 {
+        return;
+    }
+
+    if NEED_RESCHED.swap(false, Ordering::Relaxed) {
+        schedule();
+    }
+}
+
+/// Switches to the next ready thread, or to the idle thread if there is
+/// none and the current thread can't continue. Must be called with
+/// interrupts disabled.
+fn schedule() {
+    let mut scheduler = SCHEDULER.lock();
+    let Some(scheduler_ref) = scheduler.as_mut() else {
+        return;
+    };
+
+    let mut prev = scheduler_ref.current;
+    let prev_ref = unsafe { prev.as_mut() };
+    let prev_running = prev_ref.state == ThreadState::Running;
+    if prev_running && prev != scheduler_ref.idle {
+        prev_ref.state = ThreadState::Ready;
+        unsafe { scheduler_ref.ready.push(prev) };
+    }
+
+    let mut next = match unsafe { scheduler_ref.ready.pop() } {
+        Some(next) => next,
+        // The idle thread only runs when nothing else can
+        None if prev == scheduler_ref.idle => return,
+        None => scheduler_ref.idle,
+    };
+    unsafe { next.as_mut().state = ThreadState::Running };
+    scheduler_ref.slice_start = None;
+    if next == prev {
+        return;
+    }
+
+    scheduler_ref.current = next;
+    let prev_rsp = unsafe { &raw mut (*prev.as_ptr()).rsp };
+    let next_rsp = unsafe { next.as_ref().rsp };
+    // The lock is released before switching, the next thread picks up from
+    // its own call to `schedule` or from `thread_start`
+    drop(scheduler);
+    unsafe { switch_context(prev_rsp, next_rsp) };
+}
+
+/// Threads waiting for something, the building block of the blocking
+/// primitives in `wait.rs`. Waking is allowed from interrupt handlers.
+pub struct WaitQueue {
+    threads: SpinLock<ThreadQueue>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            threads: SpinLock::new(ThreadQueue::new()),
+        }
+    }
+
+    /// Blocks the current thread until `condition` returns true. The
+    /// condition is checked with the queue locked, so a wake up between the
+    /// check and blocking isn't lost, which also means it must not block or
+    /// touch the queue itself. Can't be used from interrupt handlers or the
+    /// idle thread.
+    pub fn wait_until(&self, mut condition: impl FnMut() -> bool) {
+        loop {
+            let blocked = without_interrupts(|| {
+                let mut threads = self.threads.lock();
+                if condition() {
+                    return false;
+                }
+
+                {
+                    let mut scheduler = SCHEDULER.lock();
+                    let scheduler = scheduler.as_mut().unwrap();
+                    let mut current = scheduler.current;
+                    assert!(current != scheduler.idle, "the idle thread can't block");
+                    unsafe {
+                        current.as_mut().state = ThreadState::Blocked;
+                        threads.push(current);
+                    }
+                }
+
+                drop(threads);
+                schedule();
+                true
+            });
+            if !blocked {
+                return;
+            }
+        }
+    }
+
+    /// Wakes the thread that has waited the longest, returns whether there
+    /// was one.
+    pub fn wake_one(&self) -> bool {
+        let thread = unsafe { self.threads.lock().pop() };
+        match thread {
+            Some(thread) => {
+                unblock(thread);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes every waiting thread, returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let mut threads = core::mem::replace(&mut *self.threads.lock(), ThreadQueue::new());
+        let mut woken = 0;
+        while let Some(thread) = unsafe { threads.pop() } {
+            unblock(thread);
+            woken += 1;
+        }
+
+        woken
+    }
+}
+
+// Makes a thread taken out of a wait queue runnable again. The current thread
+// can be woken by another cpu before it has switched away, it then just
+// keeps running.
+fn unblock(mut thread: NonNull<Thread>) {
+    let mut scheduler = SCHEDULER.lock();
+    let scheduler = scheduler.as_mut().unwrap();
+    let thread_ref = unsafe { thread.as_mut() };
+    if thread_ref.state != ThreadState::Blocked {
+        return;
+    }
+
+    if thread == scheduler.current {
+        thread_ref.state = ThreadState::Running;
+    } else {
+        thread_ref.state = ThreadState::Ready;
+        unsafe { scheduler.ready.push(thread) };
+    }
+
+    // Wakes from interrupt handlers switch to the woken thread once the
+    // interrupt is acknowledged, others at the next tick
+    NEED_RESCHED.store(true, Ordering::Relaxed);
+}
+
+/// Frees the stacks and thread structures of exited threads.
+fn reap() {
+    loop {
+        let dead = without_interrupts(|| {
+            let mut scheduler = SCHEDULER.lock();
+            let scheduler = scheduler.as_mut()?;
+            let thread = unsafe { scheduler.dead.pop() }?;
+            Some((thread, scheduler.thread_cache))
+        });
+        let Some((thread, thread_cache)) = dead else {
+            return;
+        };
+
+        if let Some(stack) = unsafe { thread.as_ptr().read() }.stack {
+            unsafe { VMALLOC.deallocate(stack) };
+        }
+
+        unsafe { thread_cache.deallocate(thread.cast(), Layout::new::<Thread>()) };
+    }
+}
+
+fn idle() {
+    loop {
+        reap();
+        interrupts::enable();
+        unsafe { core::arch::asm!("hlt") };
+    }
+}
+
+fn allocate_thread(
+    thread_cache: &SlabCache<&'static Buddy>,
+    id: ThreadId,
+    rsp: u64,
+    stack: Option<VirtualArea>,
+) -> Result<NonNull<Thread>, SpawnError> {
+    let ptr = thread_cache
+        .allocate(Layout::new::<Thread>())
+        .map_err(|_| SpawnError::OutOfMemory)?
+        .cast::<Thread>();
+    unsafe {
+        ptr.write(Thread {
+            id,
+            state: ThreadState::Ready,
+            rsp,
+            stack,
+            next: None,
+        })
+    };
+    Ok(ptr)
+}
+
+/// Sets up a stack that `switch_context` can switch to, which starts `f`
+/// through `thread_start` and `thread_entry`.
+fn create_thread<F: FnOnce() + Send + 'static>(
+    thread_cache: &SlabCache<&'static Buddy>,
+    id: ThreadId,
+    f: F,
+) -> Result<NonNull<Thread>, SpawnError> {
+    let layout = Layout::new::<F>();
+    if layout.size() > STACK_PAGES * 4096 / 2 {
+        return Err(SpawnError::ClosureTooLarge);
+    }
+
+    let thread = allocate_thread(thread_cache, id, 0, None)?;
+    let stack = match VMALLOC.allocate(STACK_PAGES, STACK_GUARD_PAGES, true) {
+        Ok(stack) => stack,
+        Err(_) => {
+            unsafe { thread_cache.deallocate(thread.cast(), Layout::new::<Thread>()) };
+            return Err(SpawnError::OutOfMemory);
+        }
+    };
+
+    // The closure lives at the top of the stack until the thread moves it out
+    let top = stack.end().as_u64();
+    let f_ptr = (top - layout.size() as u64) & !(layout.align() as u64 - 1);
+    unsafe { (f_ptr as *mut F).write(f) };
+
+    // Registers as popped by `switch_context`, followed by the return address.
+    // The stack is 16 byte aligned once the return address is popped
+    let frame_top = f_ptr & !0xf;
+    let frame = [
+        0,                                     // r15
+        0,                                     // r14
+        0,                                     // r13
+        f_ptr,                                 // r12
+        thread_entry::<F> as *const () as u64, // rbx
+        0,                                     // rbp
+        thread_start as *const () as u64,      // return address
+    ];
+    let rsp = frame_top - core::mem::size_of_val(&frame) as u64;
+    unsafe {
+        (rsp as *mut [u64; 7]).write(frame);
+        let thread_ref = &mut *thread.as_ptr();
+        thread_ref.rsp = rsp;
+        thread_ref.stack = Some(stack);
+    }
+
+    Ok(thread)
+}
+
+extern "C" fn thread_entry<F: FnOnce()>(f: *mut F) -> ! {
+    let f = unsafe { f.read() };
+    // Threads are always switched to with interrupts disabled
+    interrupts::enable();
+    f();
+    exit();
+}
+
+core::arch::global_asm!(
+    ".global sched_switch_context",
+    "sched_switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+    ".global sched_thread_start",
+    "sched_thread_start:",
+    "mov rdi, r12",
+    "call rbx",
+    "ud2",
+);
+
+extern "C" {
+    /// Saves the callee saved registers on the current stack, stores the
+    /// stack pointer in `prev_rsp` and restores the registers from `next_rsp`.
+    #[link_name = "sched_switch_context"]
+    fn switch_context(prev_rsp: *mut u64, next_rsp: u64);
+
+    #[link_name = "sched_thread_start"]
+    fn thread_start();
+}
+
+// Intrusive FIFO of threads, linked through `Thread::next`
+struct ThreadQueue {
+    head: Option<NonNull<Thread>>,
+    tail: Option<NonNull<Thread>>,
+}
+
+// Threads are only reached through the queues while their lock is held
+unsafe impl Send for ThreadQueue {}
+
+impl ThreadQueue {
+    const fn new() -> Self {
+        Self {
+            head: None,
+            tail: None,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The thread must not be in any other queue.
+    unsafe fn push(&mut self, mut thread: NonNull<Thread>) {
+        unsafe { thread.as_mut().next = None };
+        match self.tail {
+            Some(mut tail) => unsafe { tail.as_mut().next = Some(thread) },
+            None => self.head = Some(thread),
+        }
+
+        self.tail = Some(thread);
+    }
+
+    unsafe fn pop(&mut self) -> Option<NonNull<Thread>> {
+        let mut head = self.head?;
+        self.head = unsafe { head.as_mut().next.take() };
+        if self.head.is_none() {
+            self.tail = None;
+        }
+
+        Some(head)
+    }
+}
@@ -1,86 +1,498 @@
+use core::alloc::AllocError;
 use core::alloc::Allocator;
 use core::alloc::Layout;
+use core::fmt;
 use core::ptr::NonNull;
 
+use common::addr::VirtAddr;
 use common::frame::FrameAllocator;
+use sync::SpinLock;
 use x86_64::paging::PageTableFrameMapper;
 
-use crate::spinlock::Mutex;
+use crate::sprintln;
 use crate::FRAME_OFFSET_MAPPER;
 
-// This is a simple slab allocator, and only works on a single cpu for now.
+const FRAME_SIZE: usize = 4096;
+
+// With the slab-debug feature every object is followed by a red zone, and free
+// objects are filled with a poison pattern. Both are checked whenever an object
+// changes hands, which catches overflows, double frees and writes after free.
+pub const SLAB_DEBUG: bool = cfg!(feature = "slab-debug");
+const RED_ZONE_SIZE: usize = 16;
+const RED_ZONE_ACTIVE: u8 = 0xcc;
+const RED_ZONE_INACTIVE: u8 = 0xbb;
+const POISON_FREE: u8 = 0x6b;
+
+/// How a slab cache lays out and initialises its objects.
+#[derive(Clone, Copy, Debug)]
+pub struct SlabCacheOptions {
+    pub name: &'static str,
+    pub object_layout: Layout,
+    /// Each slab spans 2^slab_order frames
+    pub slab_order: usize,
+    /// Called on every object when its slab is created, objects are expected to
+    /// be returned to the cache in their constructed state
+    pub constructor: Option<fn(NonNull<u8>)>,
+    /// Called on every object when its slab is released
+    pub destructor: Option<fn(NonNull<u8>)>,
+}
+
+impl SlabCacheOptions {
+    pub const fn new(name: &'static str, object_layout: Layout) -> Self {
+        Self {
+            name,
+            object_layout,
+            slab_order: 0,
+            constructor: None,
+            destructor: None,
+        }
+    }
+}
+
+/// Usage numbers of a slab cache.
+#[derive(Clone, Copy, Debug)]
+pub struct SlabCacheStats {
+    pub objects_in_use: usize,
+    /// Objects held by the per cpu magazines in front of the cache, which
+    /// are free but still take up their slabs
+    pub objects_cached: usize,
+    /// Includes objects that were cached at the time
+    pub peak_objects_in_use: usize,
+    pub slabs: usize,
+    pub peak_slabs: usize,
+    pub objects_per_slab: usize,
+    pub object_size: usize,
+    pub slab_size: usize,
+}
+
+impl SlabCacheStats {
+    pub fn bytes_held(&self) -> usize {
+        self.slabs * self.slab_size
+    }
+}
+
+// This is a simple slab allocator, the slab lists are shared between all cpus behind a lock,
+// per cpu caching is done in front of it by the magazine layer.
 // Basically just a number of freestanding frames that have fixed size slots of a particular size.
 // Empty slabs are returned to frame allocator.
 // Partially full slabs are allocated from
 // Full slabs are ignored, and become partailly full when freed from.
-#[derive(Debug)]
 pub struct SlabCache<F: FrameAllocator> {
-    pub inner: Mutex<SlabCacheInner>,
+    pub inner: SpinLock<SlabCacheInner>,
     pub frame_allocator: F,
+    pub name: &'static str,
     pub object_layout: Layout,
+    slot_size: usize,
+    // Offset of the freelist link within a slot. Objects with a constructor
+    // keep their constructed state while free, so the link goes after them
+    free_offset: usize,
+    red_zone_size: usize,
+    // Poisoning would destroy the constructed state, so it is skipped for
+    // caches with a constructor
+    poison: bool,
+    slab_order: usize,
+    constructor: Option<fn(NonNull<u8>)>,
+    destructor: Option<fn(NonNull<u8>)>,
+}
+
+impl<F: FrameAllocator> fmt::Debug for SlabCache<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SlabCache")
+            .field("name", &self.name)
+            .field("object_layout", &self.object_layout)
+            .field("slab_order", &self.slab_order)
+            .field("inner", &self.inner)
+            .finish()
+    }
 }
 
 impl<F: FrameAllocator> SlabCache<F> {
-    pub fn new(frame_allocator: F, object_layout: Layout) -> Self {
+    pub const fn new(frame_allocator: F, options: SlabCacheOptions) -> Self {
+        let link_size = core::mem::size_of::<Freelist>();
+        let object_size = options.object_layout.size();
+        let object_align = options.object_layout.align();
+        let red_zone_size = if SLAB_DEBUG { RED_ZONE_SIZE } else { 0 };
+        let (slot_size, free_offset) =
+            if options.constructor.is_some() || options.destructor.is_some() || SLAB_DEBUG {
+                let free_offset = (object_size + red_zone_size).next_multiple_of(link_size);
+                (free_offset + link_size, free_offset)
+            } else if object_size < link_size {
+                (link_size, 0)
+            } else {
+                (object_size, 0)
+            };
+        let slot_size = slot_size.next_multiple_of(object_align);
+
+        // The red zone can push an object out of a slab it would otherwise fit
+        // in, so make sure there is room for at least one
+        let mut slab_order = options.slab_order;
+        while (FRAME_SIZE << slab_order) < objects_offset(options.object_layout) + slot_size {
+            slab_order += 1;
+        }
+
         Self {
-            inner: Mutex::new(SlabCacheInner::new()),
+            inner: SpinLock::new(SlabCacheInner::new()),
             frame_allocator,
-            object_layout,
+            name: options.name,
+            object_layout: options.object_layout,
+            slot_size,
+            free_offset,
+            red_zone_size,
+            poison: SLAB_DEBUG && options.constructor.is_none(),
+            slab_order,
+            constructor: options.constructor,
+            destructor: options.destructor,
+        }
+    }
+
+    pub fn stats(&self) -> SlabCacheStats {
+        let inner = self.inner.lock();
+        SlabCacheStats {
+            objects_in_use: inner.objects_in_use,
+            objects_cached: 0,
+            peak_objects_in_use: inner.peak_objects_in_use,
+            slabs: inner.slabs,
+            peak_slabs: inner.peak_slabs,
+            objects_per_slab: (self.slab_size() - objects_offset(self.object_layout))
+                / self.slot_size,
+            object_size: self.object_layout.size(),
+            slab_size: self.slab_size(),
+        }
+    }
+
+    /// Releases all slabs held by the cache.
+    ///
+    /// # Safety
+    ///
+    /// There must be no objects allocated from the cache.
+    pub unsafe fn destroy(&self) {
+        let mut inner = self.inner.lock();
+        if let Some(active) = inner.active.take() {
+            self.release_slab(&mut inner, active);
+        }
+
+        while let Some(slab) = inner.partial.pop() {
+            self.release_slab(&mut inner, slab);
+        }
+
+        while let Some(slab) = inner.full.pop() {
+            self.release_slab(&mut inner, slab);
+        }
+    }
+
+    fn slab_size(&self) -> usize {
+        FRAME_SIZE << self.slab_order
+    }
+
+    fn create_slab(&self, inner: &mut SlabCacheInner) -> Result<NonNull<Slab>, AllocError> {
+        // TODO: use page allocator not a frame allocator
+        let frame = self
+            .frame_allocator
+            .allocate_frames(1 << self.slab_order)
+            .map_err(|_| AllocError)?;
+        debug_assert!(
+            frame.as_u64().is_multiple_of(self.slab_size() as u64),
+            "slab {} not aligned to its size",
+            self.name
+        );
+        let offset = objects_offset(self.object_layout);
+        // TODO: make generic over page allocator instead of frame allocator
+        let mut page = FRAME_OFFSET_MAPPER.frame_to_page(frame);
+        let slab_ptr = NonNull::new(page.as_ptr_mut::<Slab>()).unwrap();
+        let slab = Slab::new(
+            page.as_u64() + offset as u64,
+            (self.slab_size() - offset) as u64,
+            self.slot_size,
+            self.free_offset,
+        );
+        if let Some(constructor) = self.constructor {
+            slab.objects().for_each(constructor);
+        }
+
+        if SLAB_DEBUG {
+            slab.objects().for_each(|object| self.debug_init(object));
+        }
+
+        unsafe { slab_ptr.write(slab) };
+        inner.slabs += 1;
+        inner.peak_slabs = inner.peak_slabs.max(inner.slabs);
+        Ok(slab_ptr)
+    }
+
+    fn release_slab(&self, inner: &mut SlabCacheInner, slab: NonNull<Slab>) {
+        if let Some(destructor) = self.destructor {
+            unsafe { slab.as_ref() }.objects().for_each(destructor);
         }
+
+        let frame = FRAME_OFFSET_MAPPER.page_to_frame(VirtAddr::new(slab.as_ptr() as u64));
+        // If the frame allocator refuses the frame it is leaked, which is all we can do here
+        let _ = self
+            .frame_allocator
+            .deallocate_frames(frame, 1 << self.slab_order);
+        inner.slabs -= 1;
+    }
+
+    /// The slab `ptr` was allocated from, or `None` if it isn't an object of
+    /// this cache.
+    fn slab_containing(&self, ptr: NonNull<u8>) -> Option<NonNull<Slab>> {
+        // The frame allocator aligns blocks to their size, so the header is
+        // found by rounding the object address down to the slab
+        let addr = ptr.as_ptr() as usize & !(self.slab_size() - 1);
+        let slab = unsafe { NonNull::new_unchecked(addr as *mut Slab) };
+        let slab_ref = unsafe { slab.as_ref() };
+        (slab_ref.slot_size == self.slot_size && slab_ref.contains(ptr)).then_some(slab)
     }
 }
 
-unsafe impl<F: FrameAllocator> Allocator for SlabCache<F> {
-    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+impl<F: FrameAllocator> SlabCache<F> {
+    fn debug_init(&self, object: NonNull<u8>) {
+        if self.poison {
+            unsafe { self.object_bytes(object) }.fill(POISON_FREE);
+        }
+
+        unsafe { self.red_zone(object) }.fill(RED_ZONE_INACTIVE);
+    }
+
+    fn debug_allocate(&self, object: NonNull<u8>) {
+        let red_zone = unsafe { self.red_zone(object) };
+        if red_zone.iter().any(|b| *b != RED_ZONE_INACTIVE) {
+            self.debug_report("red zone of free object overwritten", object);
+        }
+
+        if self.poison
+            && unsafe { self.object_bytes(object) }
+                .iter()
+                .any(|b| *b != POISON_FREE)
+        {
+            self.debug_report("write after free", object);
+        }
+
+        red_zone.fill(RED_ZONE_ACTIVE);
+    }
+
+    /// Returns whether the object can be freed, which is not the case if it already is free
+    fn debug_deallocate(&self, object: NonNull<u8>) -> bool {
+        let red_zone = unsafe { self.red_zone(object) };
+        if red_zone.iter().all(|b| *b == RED_ZONE_INACTIVE) {
+            self.debug_report("double free", object);
+            return false;
+        }
+
+        if red_zone.iter().any(|b| *b != RED_ZONE_ACTIVE) {
+            self.debug_report("overflow into red zone", object);
+        }
+
+        self.debug_init(object);
+        true
+    }
+
+    fn debug_report(&self, error: &str, object: NonNull<u8>) {
+        sprintln!(
+            "slab {}: {} at object {:#x} (size {})",
+            self.name,
+            error,
+            object.as_ptr() as usize,
+            self.object_layout.size()
+        );
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn object_bytes(&self, object: NonNull<u8>) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(object.as_ptr(), self.object_layout.size()) }
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    unsafe fn red_zone(&self, object: NonNull<u8>) -> &mut [u8] {
+        unsafe {
+            core::slice::from_raw_parts_mut(
+                object.as_ptr().add(self.object_layout.size()),
+                self.red_zone_size,
+            )
+        }
+    }
+}
+
+impl<F: FrameAllocator> SlabCache<F> {
+    /// Fills `objects` with freshly allocated objects while only taking the
+    /// lock once, returns how many objects could be allocated.
+    pub fn allocate_bulk(&self, objects: &mut [NonNull<u8>]) -> usize {
         let mut inner = self.inner.lock();
-        let mut active = if let Some(active) = inner.active {
-            active
-        } else {
-            // TODO: use page allocator not a frame allocator
-            let frame = self.frame_allocator.allocate_frame().unwrap();
-            let slab_layout = Layout::new::<Slab>();
-            let (_, offset) = slab_layout.extend(layout).unwrap();
-            // TODO: make generic over page allocator instead of frame allocator
-            let mut page = FRAME_OFFSET_MAPPER.frame_to_page(frame);
-            let mut slab_ptr = NonNull::new(page.as_ptr_mut::<Slab>()).unwrap();
-            unsafe {
-                *slab_ptr.as_mut() = Slab::new(
-                    page.as_u64() + offset as u64,
-                    4096 - offset as u64,
-                    self.object_layout,
-                );
+        for (i, object) in objects.iter_mut().enumerate() {
+            match self.allocate_object(&mut inner) {
+                Ok(ptr) => *object = ptr,
+                Err(_) => return i,
+            }
+        }
+
+        objects.len()
+    }
+
+    /// Returns all of `objects` to their slabs while only taking the lock once.
+    ///
+    /// # Safety
+    ///
+    /// Every object must have been allocated from this cache and not already freed.
+    pub unsafe fn deallocate_bulk(&self, objects: &[NonNull<u8>]) {
+        let mut inner = self.inner.lock();
+        for object in objects {
+            unsafe { self.deallocate_object(&mut inner, *object) };
+        }
+    }
+
+    fn allocate_object(&self, inner: &mut SlabCacheInner) -> Result<NonNull<u8>, AllocError> {
+        let mut active = match inner.active {
+            Some(active) => active,
+            None => {
+                let slab = match inner.partial.pop() {
+                    Some(slab) => slab,
+                    None => self.create_slab(inner)?,
+                };
+                inner.active = Some(slab);
+                slab
             }
-            inner.active = Some(slab_ptr);
-            slab_ptr
         };
-        let active = unsafe { active.as_mut() };
-        let ptr = active.allocate(layout);
-        if active.freelist.is_none() {
+        let active_ref = unsafe { active.as_mut() };
+        let ptr = active_ref.allocate();
+        if active_ref.is_full() {
             inner.active = None;
+            unsafe { inner.full.push(active) };
         }
 
+        if SLAB_DEBUG {
+            self.debug_allocate(ptr);
+        }
+
+        inner.objects_in_use += 1;
+        inner.peak_objects_in_use = inner.peak_objects_in_use.max(inner.objects_in_use);
         Ok(ptr)
     }
 
-    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-        todo!()
+    unsafe fn deallocate_object(&self, inner: &mut SlabCacheInner, ptr: NonNull<u8>) {
+        let Some(mut slab) = self.slab_containing(ptr) else {
+            self.debug_report("free of an object from another cache", ptr);
+            return;
+        };
+
+        if SLAB_DEBUG && !self.debug_deallocate(ptr) {
+            return;
+        }
+
+        let slab_ref = unsafe { slab.as_mut() };
+        let was_full = slab_ref.is_full();
+        unsafe { slab_ref.deallocate(ptr) };
+        inner.objects_in_use -= 1;
+
+        // The active slab is kept around even when empty, so that a single
+        // allocate/deallocate pair doesn't bounce a frame to and from the
+        // frame allocator
+        if inner.active == Some(slab) {
+            return;
+        }
+
+        if was_full {
+            unsafe { inner.full.remove(slab) };
+            if slab_ref.is_empty() {
+                self.release_slab(inner, slab);
+            } else {
+                unsafe { inner.partial.push(slab) };
+            }
+        } else if slab_ref.is_empty() {
+            unsafe { inner.partial.remove(slab) };
+            self.release_slab(inner, slab);
+        }
+    }
+}
+
+unsafe impl<F: FrameAllocator> Allocator for SlabCache<F> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = self.allocate_object(&mut self.inner.lock())?;
+        Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, _layout: Layout) {
+        unsafe { self.deallocate_object(&mut self.inner.lock(), ptr) };
     }
 }
 
+const fn objects_offset(object_layout: Layout) -> usize {
+    core::mem::size_of::<Slab>().next_multiple_of(object_layout.align())
+}
+
 #[derive(Debug)]
 pub struct SlabCacheInner {
     active: Option<NonNull<Slab>>,
-    partial: Option<NonNull<Slab>>,
-    full: Option<NonNull<Slab>>,
+    partial: SlabList,
+    full: SlabList,
+    objects_in_use: usize,
+    peak_objects_in_use: usize,
+    slabs: usize,
+    peak_slabs: usize,
 }
 
+// The slabs are only reachable through the cache, so moving the list heads
+// between threads moves ownership of the slabs along with them
+unsafe impl Send for SlabCacheInner {}
+
 impl SlabCacheInner {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Self {
             active: None,
-            partial: None,
-            full: None,
+            partial: SlabList::new(),
+            full: SlabList::new(),
+            objects_in_use: 0,
+            peak_objects_in_use: 0,
+            slabs: 0,
+            peak_slabs: 0,
+        }
+    }
+}
+
+/// Intrusive doubly linked list of slabs, linked through the slab headers
+#[derive(Debug)]
+struct SlabList {
+    head: Option<NonNull<Slab>>,
+}
+
+impl SlabList {
+    const fn new() -> Self {
+        Self { head: None }
+    }
+
+    /// # Safety
+    ///
+    /// `slab` must point to a valid slab that is not part of any list.
+    unsafe fn push(&mut self, mut slab: NonNull<Slab>) {
+        unsafe {
+            slab.as_mut().prev = None;
+            slab.as_mut().next = self.head;
+            if let Some(mut head) = self.head {
+                head.as_mut().prev = Some(slab);
+            }
+        }
+        self.head = Some(slab);
+    }
+
+    fn pop(&mut self) -> Option<NonNull<Slab>> {
+        let slab = self.head?;
+        unsafe { self.remove(slab) };
+        Some(slab)
+    }
+
+    /// # Safety
+    ///
+    /// `slab` must be part of this list.
+    unsafe fn remove(&mut self, mut slab: NonNull<Slab>) {
+        let slab = unsafe { slab.as_mut() };
+        match slab.prev {
+            Some(mut prev) => unsafe { prev.as_mut().next = slab.next },
+            None => self.head = slab.next,
+        }
+        if let Some(mut next) = slab.next {
+            unsafe { next.as_mut().prev = slab.prev };
         }
+
+        slab.prev = None;
+        slab.next = None;
     }
 }
 
@@ -89,16 +501,24 @@ impl SlabCacheInner {
 struct Slab {
     freelist: Option<NonNull<Freelist>>,
     next: Option<NonNull<Slab>>,
+    prev: Option<NonNull<Slab>>,
+    base: u64,
+    slot_size: usize,
+    free_offset: usize,
+    in_use: usize,
+    capacity: usize,
 }
 
 impl Slab {
-    pub fn new(base: u64, size: u64, object_layout: Layout) -> Self {
-        let u64_layout = Layout::new::<u64>();
-        let object_size = u64_layout.size().max(object_layout.size());
+    pub fn new(base: u64, size: u64, slot_size: usize, free_offset: usize) -> Self {
+        let capacity = size / slot_size as u64;
         let mut freelist = None;
-        for i in 0..size / object_size as u64 {
-            let cur =
-                unsafe { NonNull::new_unchecked((base + i * object_size as u64) as *mut Freelist) };
+        for i in 0..capacity {
+            let cur = unsafe {
+                NonNull::new_unchecked(
+                    (base + i * slot_size as u64 + free_offset as u64) as *mut Freelist,
+                )
+            };
             unsafe {
                 cur.write(Freelist { next: freelist });
             };
@@ -108,22 +528,58 @@ impl Slab {
         Self {
             freelist,
             next: None,
+            prev: None,
+            base,
+            slot_size,
+            free_offset,
+            in_use: 0,
+            capacity: capacity as usize,
         }
     }
 
-    fn allocate(&mut self, layout: Layout) -> NonNull<[u8]> {
+    fn objects(&self) -> impl Iterator<Item = NonNull<u8>> {
+        let base = self.base as usize;
+        let slot_size = self.slot_size;
+        (0..self.capacity)
+            .map(move |i| unsafe { NonNull::new_unchecked((base + i * slot_size) as *mut u8) })
+    }
+
+    fn contains(&self, ptr: NonNull<u8>) -> bool {
+        let offset = (ptr.as_ptr() as usize).wrapping_sub(self.base as usize);
+        offset.is_multiple_of(self.slot_size) && offset / self.slot_size < self.capacity
+    }
+
+    fn is_full(&self) -> bool {
+        self.in_use == self.capacity
+    }
+
+    fn is_empty(&self) -> bool {
+        self.in_use == 0
+    }
+
+    fn allocate(&mut self) -> NonNull<u8> {
         if let Some(freelist) = self.freelist {
             self.freelist = unsafe { freelist.as_ref() }.next;
-            unsafe {
-                NonNull::new_unchecked(core::ptr::slice_from_raw_parts_mut(
-                    freelist.cast().as_mut(),
-                    layout.size(),
-                ))
-            }
+            self.in_use += 1;
+            unsafe { freelist.cast::<u8>().sub(self.free_offset) }
         } else {
             panic!()
         }
     }
+
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated from this slab and not already freed.
+    unsafe fn deallocate(&mut self, ptr: NonNull<u8>) {
+        let link = unsafe { ptr.add(self.free_offset) }.cast::<Freelist>();
+        unsafe {
+            link.write(Freelist {
+                next: self.freelist,
+            });
+        }
+        self.freelist = Some(link);
+        self.in_use -= 1;
+    }
 }
 
 #[derive(Debug)]
@@ -131,3 +587,129 @@ impl Slab {
 struct Freelist {
     next: Option<NonNull<Freelist>>,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    use common::addr::PhysAddr;
+    use common::frame::FrameAllocError;
+
+    use super::*;
+
+    // Hands out host memory, aligned to its size like the buddy allocator
+    // does. Tests map frames at their own address
+    struct HostFrames {
+        allocated: AtomicUsize,
+    }
+
+    impl HostFrames {
+        fn new() -> Self {
+            Self {
+                allocated: AtomicUsize::new(0),
+            }
+        }
+
+        fn allocated(&self) -> usize {
+            self.allocated.load(Ordering::Relaxed)
+        }
+
+        fn layout(num_frames: usize) -> Layout {
+            let size = num_frames.next_power_of_two() * FRAME_SIZE;
+            Layout::from_size_align(size, size).unwrap()
+        }
+    }
+
+    impl FrameAllocator for HostFrames {
+        fn allocate_frames(&self, num_frames: usize) -> Result<PhysAddr, FrameAllocError> {
+            let ptr = unsafe { std::alloc::alloc(Self::layout(num_frames)) };
+            if ptr.is_null() {
+                return Err(FrameAllocError);
+            }
+
+            self.allocated.fetch_add(num_frames, Ordering::Relaxed);
+            Ok(PhysAddr::new(ptr as u64))
+        }
+
+        fn deallocate_frames(
+            &self,
+            addr: PhysAddr,
+            num_frames: usize,
+        ) -> Result<(), FrameAllocError> {
+            unsafe { std::alloc::dealloc(addr.as_u64() as *mut u8, Self::layout(num_frames)) };
+            self.allocated.fetch_sub(num_frames, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn destroy_releases_every_slab() {
+        let frames = HostFrames::new();
+        let layout = Layout::new::<[u64; 32]>();
+        let cache = SlabCache::new(&frames, SlabCacheOptions::new("destroy", layout));
+
+        let objects: [_; 64] = core::array::from_fn(|_| cache.allocate(layout).unwrap());
+        assert!(cache.stats().slabs > 1);
+        for object in objects {
+            unsafe { cache.deallocate(object.cast(), layout) };
+        }
+
+        unsafe { cache.destroy() };
+        assert_eq!(cache.stats().slabs, 0);
+        assert_eq!(frames.allocated(), 0);
+    }
+
+    #[test]
+    fn multi_frame_slabs() {
+        let frames = HostFrames::new();
+        let layout = Layout::new::<[u8; 1000]>();
+        let cache = SlabCache::new(
+            &frames,
+            SlabCacheOptions {
+                slab_order: 2,
+                ..SlabCacheOptions::new("order-2", layout)
+            },
+        );
+
+        let objects: [_; 40] = core::array::from_fn(|_| cache.allocate(layout).unwrap().cast());
+        assert!(cache.stats().slabs > 1);
+        for object in objects {
+            // Objects past the first frame of a slab still find its header
+            let slab = cache.slab_containing(object).unwrap();
+            let offset = object.as_ptr() as usize - slab.as_ptr() as usize;
+            assert!(offset < cache.slab_size());
+        }
+
+        for object in objects.into_iter().rev() {
+            unsafe { cache.deallocate(object, layout) };
+        }
+        assert_eq!(cache.stats().objects_in_use, 0);
+        unsafe { cache.destroy() };
+        assert_eq!(frames.allocated(), 0);
+    }
+
+    #[test]
+    fn foreign_objects_are_rejected() {
+        let frames = HostFrames::new();
+        let small = Layout::new::<[u64; 4]>();
+        let large = Layout::new::<[u64; 8]>();
+        let small_cache = SlabCache::new(&frames, SlabCacheOptions::new("small", small));
+        let large_cache = SlabCache::new(&frames, SlabCacheOptions::new("large", large));
+
+        let object = small_cache.allocate(small).unwrap().cast::<u8>();
+        assert!(small_cache.slab_containing(object).is_some());
+        assert!(large_cache.slab_containing(object).is_none());
+        // Not the start of an object
+        assert!(small_cache
+            .slab_containing(unsafe { object.add(8) })
+            .is_none());
+
+        unsafe {
+            small_cache.deallocate(object, small);
+            small_cache.destroy();
+            large_cache.destroy();
+        }
+        assert_eq!(frames.allocated(), 0);
+    }
+}
@@ -0,0 +1,261 @@
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+
+use common::addr::PhysAddr;
+use common::addr::VirtAddr;
+use sync::Once;
+use x86_64::control::Cr3;
+use x86_64::interrupts;
+use x86_64::interrupts::without_interrupts;
+use x86_64::tlb;
+
+use crate::interrupt;
+use crate::percpu;
+use crate::pit;
+use crate::sprintln;
+use crate::LAPIC;
+use crate::VMALLOC;
+
+pub const MAX_CPUS: usize = 16;
+
+const AP_STACK_PAGES: usize = 16;
+
+// Offsets of the values the trampoline reads, see trampoline.nasm
+const SEGMENT_BASE: usize = 0x800;
+const STACK_ROOT: usize = 0x808;
+const KERNEL_START: usize = 0x810;
+const PML4_ADDR: usize = 0x818;
+const CPU_INDEX: usize = 0x820;
+
+// Interrupt command register values
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_INIT: u32 = 0x0000_4500;
+const ICR_STARTUP: u32 = 0x0000_4600;
+const ICR_FIXED: u32 = 0x0000_4000;
+#[cfg(feature = "self-test")]
+const ICR_SELF: u32 = 0b01 << 18;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+
+const PAGE_SIZE: u64 = 4096;
+
+// A TLB shootdown in progress, see `flush_tlb_others`. The lock is a plain
+// flag so that a cpu waiting for it can keep flushing for the holder
+static SHOOTDOWN_VECTOR: Once<u8> = Once::new();
+static SHOOTDOWN_LOCK: AtomicBool = AtomicBool::new(false);
+static SHOOTDOWN_START: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_PAGES: AtomicU64 = AtomicU64::new(0);
+// Cpus that haven't flushed the range yet
+static SHOOTDOWN_PENDING: AtomicU64 = AtomicU64::new(0);
+
+/// Cpus that have finished their bring-up, indexed by the cpu index handed
+/// out by `start_aps`. The bootstrap processor is always cpu 0.
+pub static ONLINE_CPUS: CpuMask = CpuMask::new();
+
+#[derive(Debug)]
+pub struct CpuMask(AtomicU64);
+
+impl CpuMask {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    pub fn set(&self, cpu: usize) {
+        self.0.fetch_or(1 << cpu, Ordering::Release);
+    }
+
+    pub fn contains(&self, cpu: usize) -> bool {
+        self.0.load(Ordering::Acquire) & (1 << cpu) != 0
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::Acquire).count_ones() as usize
+    }
+
+    pub fn bits(&self) -> u64 {
+        self.0.load(Ordering::Acquire)
+    }
+}
+
+/// Sets up the vector for TLB shootdowns, has to run before `start_aps`.
+pub fn init_shootdown() {
+    let vector = interrupt::allocate_vector().unwrap();
+    interrupt::register(vector, shootdown_interrupt, 0).unwrap();
+    SHOOTDOWN_VECTOR.call_once(|| vector);
+}
+
+/// Invalidates `pages` pages starting at `start` in the TLBs of all other
+/// online cpus, and waits until they are done. The other cpus have to take an
+/// interrupt for this, so the caller must not hold a lock that they could be
+/// spinning on with interrupts disabled.
+pub fn flush_tlb_others(start: VirtAddr, pages: usize) {
+    let Some(&vector) = SHOOTDOWN_VECTOR.get() else {
+        return;
+    };
+
+    without_interrupts(|| {
+        let others = ONLINE_CPUS.bits() & !(1 << percpu::cpu_index());
+        if others == 0 {
+            return;
+        }
+
+        while SHOOTDOWN_LOCK
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            // The holder may be waiting for this cpu
+            handle_shootdown();
+            core::hint::spin_loop();
+        }
+
+        SHOOTDOWN_START.store(start.as_u64(), Ordering::Relaxed);
+        SHOOTDOWN_PAGES.store(pages as u64, Ordering::Relaxed);
+        SHOOTDOWN_PENDING.store(others, Ordering::Release);
+        // The destination is ignored with a shorthand
+        send_ipi(0, ICR_ALL_EXCLUDING_SELF | ICR_FIXED | vector as u32);
+        while SHOOTDOWN_PENDING.load(Ordering::Acquire) != 0 {
+            core::hint::spin_loop();
+        }
+
+        SHOOTDOWN_LOCK.store(false, Ordering::Release);
+    });
+}
+
+fn shootdown_interrupt(_: usize) -> bool {
+    handle_shootdown();
+    true
+}
+
+// Flushes the current range if the current cpu is still asked to
+fn handle_shootdown() {
+    let cpu_bit = 1 << percpu::cpu_index();
+    if SHOOTDOWN_PENDING.load(Ordering::Acquire) & cpu_bit == 0 {
+        return;
+    }
+
+    let start = SHOOTDOWN_START.load(Ordering::Relaxed);
+    for i in 0..SHOOTDOWN_PAGES.load(Ordering::Relaxed) {
+        tlb::flush(VirtAddr::new(start + i * PAGE_SIZE));
+    }
+    SHOOTDOWN_PENDING.fetch_and(!cpu_bit, Ordering::Release);
+}
+
+/// Starts every processor in `apic_ids` other than the current one, one at a
+/// time, through the trampoline copied to `trampoline_frame`.
+pub fn start_aps(apic_ids: &[u8], trampoline_frame: PhysAddr) {
+    ONLINE_CPUS.set(0);
+
+    if trampoline_frame.as_u64() >= 0x100000 {
+        panic!(
+            "trampoline must be loaded in a frame below 1MB {:x?}",
+            trampoline_frame
+        );
+    }
+
+    // The trampoline runs in real mode, so it is accessed through its identity mapping
+    let trampoline_code = include_bytes!("../trampoline.bin");
+    let trampoline =
+        unsafe { core::slice::from_raw_parts_mut(trampoline_frame.as_u64() as *mut u8, 4096) };
+    trampoline[..trampoline_code.len()].copy_from_slice(trampoline_code);
+    write_u64(trampoline, SEGMENT_BASE, trampoline_frame.as_u64());
+    write_u64(trampoline, KERNEL_START, ap_entry as *const () as u64);
+    write_u64(trampoline, PML4_ADDR, Cr3::read().pba_pml4);
+
+    let bsp_apic_id = LAPIC.with(|lapic| lapic.read_id());
+    let mut next_cpu = 1;
+    for &apic_id in apic_ids.iter().filter(|id| **id != bsp_apic_id) {
+        if next_cpu == MAX_CPUS {
+            sprintln!("Ignoring cpu with apic id {}, too many cpus", apic_id);
+            continue;
+        }
+
+        let stack = match VMALLOC.allocate(AP_STACK_PAGES, 1, true) {
+            Ok(stack) => stack,
+            Err(error) => {
+                sprintln!("Failed to allocate stack for cpu {}: {:?}", next_cpu, error);
+                return;
+            }
+        };
+        write_u64(trampoline, STACK_ROOT, stack.end().as_u64());
+        write_u64(trampoline, CPU_INDEX, next_cpu as u64);
+
+        if start_ap(apic_id, next_cpu, trampoline_frame) {
+            sprintln!("Cpu {} (apic id {}) is online", next_cpu, apic_id);
+            // The stack belongs to the cpu from now on and is never freed
+            next_cpu += 1;
+        } else {
+            sprintln!("Cpu with apic id {} did not start", apic_id);
+            unsafe { VMALLOC.deallocate(stack) };
+        }
+    }
+
+    sprintln!("{} cpus online", ONLINE_CPUS.count());
+}
+
+/// Runs the INIT-SIPI-SIPI sequence and waits for the cpu to report in.
+fn start_ap(apic_id: u8, cpu: usize, trampoline_frame: PhysAddr) -> bool {
+    let vector = (trampoline_frame.as_u64() / 4096) as u32;
+
+    send_ipi(apic_id, ICR_INIT);
+    pit::delay_us(10_000);
+
+    for _ in 0..2 {
+        send_ipi(apic_id, ICR_STARTUP | vector);
+        pit::delay_us(200);
+        if ONLINE_CPUS.contains(cpu) {
+            return true;
+        }
+    }
+
+    // Give the cpu some more time to get through the trampoline
+    for _ in 0..100 {
+        if ONLINE_CPUS.contains(cpu) {
+            return true;
+        }
+
+        pit::delay_us(1_000);
+    }
+
+    false
+}
+
+/// Raises `vector` on the current cpu through its local apic.
+#[cfg(feature = "self-test")]
+pub fn send_self_ipi(vector: u8) {
+    send_ipi(0, ICR_SELF | ICR_FIXED | vector as u32);
+}
+
+fn send_ipi(apic_id: u8, icr_low: u32) {
+    LAPIC.with(|lapic| {
+        lapic.write_icr_high((apic_id as u32) << 24);
+        lapic.write_icr_low(icr_low);
+        while lapic.read_icr_low() & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+fn write_u64(trampoline: &mut [u8], offset: usize, value: u64) {
+    trampoline[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+// Entered from the trampoline in long mode, on the stack allocated in `start_aps`
+extern "C" fn ap_entry(cpu: usize) -> ! {
+    unsafe { percpu::init(cpu) };
+
+    crate::init_cpu_protection();
+    // The IST gates of the IDT need the TSS that comes with the GDT
+    crate::init_gdt();
+    interrupt::load();
+    crate::init_lapic();
+
+    ONLINE_CPUS.set(cpu);
+    interrupts::enable();
+
+    // Threads only run on the bootstrap processor, see `sched`
+    loop {
+        unsafe {
+            core::arch::asm!("hlt");
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use core::ops::Add;
+use core::ops::Sub;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+use acpi::tables::Fadt;
+use sync::Once;
+use x86_64::cpuid::CpuFeatures;
+use x86_64::port::PortReadOnly;
+use x86_64::tsc::rdtsc;
+
+use crate::pit;
+use crate::sprintln;
+use crate::LAPIC;
+
+pub const PM_TIMER_FREQUENCY: u64 = 3_579_545;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+// How long the reference is watched while calibrating, longer is more precise
+const CALIBRATION_US: u64 = 10_000;
+
+// The local apic timer counts down at the bus clock divided by 16
+const LAPIC_TIMER_DIVIDE_BY_16: u32 = 0b0011;
+const LAPIC_TIMER_MASKED: u32 = 1 << 16;
+const LAPIC_TIMER_PERIODIC: u32 = 1 << 17;
+
+static TSC: Once<Tsc> = Once::new();
+static PM_TIMER: Once<PmTimer> = Once::new();
+// The clock behind `Instant`, picked by `init`
+static CLOCK: Once<Clock> = Once::new();
+static LAPIC_TIMER_FREQUENCY: Once<u64> = Once::new();
+
+/// A free running counter with a known frequency.
+pub trait ClockSource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Ticks per second.
+    fn frequency(&self) -> u64;
+
+    /// The current count, which never goes backwards.
+    fn read(&self) -> u64;
+}
+
+/// The time stamp counter, only a good clock when it is invariant, i.e. keeps
+/// the same rate in every power state.
+#[derive(Debug)]
+pub struct Tsc {
+    frequency: u64,
+}
+
+impl ClockSource for Tsc {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn frequency(&self) -> u64 {
+        self.frequency
+    }
+
+    fn read(&self) -> u64 {
+        rdtsc()
+    }
+}
+
+/// The ACPI power management timer, a 24 or 32 bit counter at a fixed
+/// frequency. It is extended to 64 bits in software, which requires it to be
+/// read at least once per wrap around, about every 4.7 seconds for 24 bits.
+/// The timer tick takes care of that.
+#[derive(Debug)]
+pub struct PmTimer {
+    port: u16,
+    mask: u64,
+    // The extended count as of the last read
+    last: AtomicU64,
+}
+
+impl PmTimer {
+    fn new(port: u16, bits: u32) -> Self {
+        let pm_timer = Self {
+            port,
+            mask: (1 << bits) - 1,
+            last: AtomicU64::new(0),
+        };
+        pm_timer
+            .last
+            .store(pm_timer.read_counter(), Ordering::Relaxed);
+        pm_timer
+    }
+
+    fn read_counter(&self) -> u64 {
+        unsafe { PortReadOnly::<u32>::new(self.port).read() as u64 & self.mask }
+    }
+}
+
+impl ClockSource for PmTimer {
+    fn name(&self) -> &'static str {
+        "acpi_pm"
+    }
+
+    fn frequency(&self) -> u64 {
+        PM_TIMER_FREQUENCY
+    }
+
+    fn read(&self) -> u64 {
+        let mut last = self.last.load(Ordering::Relaxed);
+        loop {
+            let elapsed = self.read_counter().wrapping_sub(last) & self.mask;
+            match self.last.compare_exchange_weak(
+                last,
+                last + elapsed,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return last + elapsed,
+                Err(current) => last = current,
+            }
+        }
+    }
+}
+
+struct Clock {
+    source: &'static dyn ClockSource,
+    // Count at `init`, `Instant`s are relative to it
+    start: u64,
+}
+
+/// A point on the monotonic clock, measured from when `init` picked the
+/// clock source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    pub fn now() -> Self {
+        let clock = CLOCK.get().expect("time::init has to run first");
+        let ticks = clock.source.read() - clock.start;
+        let nanos = ticks as u128 * NANOS_PER_SEC as u128 / clock.source.frequency() as u128;
+        Self {
+            nanos: nanos as u64,
+        }
+    }
+
+    /// The time from `earlier` to `self`, zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        Some(Self {
+            nanos: self.nanos.checked_add(nanos)?,
+        })
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        Some(Self {
+            nanos: self.nanos.checked_sub(nanos)?,
+        })
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Self::Output {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Self::Output {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Self::Output {
+        self.duration_since(earlier)
+    }
+}
+
+// What the other timers are measured against
+enum Reference {
+    Pit,
+    PmTimer(&'static PmTimer),
+}
+
+impl Reference {
+    fn name(&self) -> &'static str {
+        match self {
+            Reference::Pit => "pit",
+            Reference::PmTimer(pm_timer) => pm_timer.name(),
+        }
+    }
+
+    fn delay_us(&self, us: u64) {
+        match self {
+            Reference::Pit => pit::delay_us(us),
+            Reference::PmTimer(pm_timer) => {
+                let ticks = (us * PM_TIMER_FREQUENCY).div_ceil(1_000_000);
+                let start = pm_timer.read();
+                while pm_timer.read() - start < ticks {
+                    core::hint::spin_loop();
+                }
+            }
+        }
+    }
+}
+
+/// Calibrates the TSC and the local apic timer of the current cpu against the
+/// ACPI PM timer, or the PIT if there is none, and picks the clock source
+/// behind `Instant`. The local apic has to be set up.
+pub fn init(fadt: Option<&Fadt>, cpu_features: &CpuFeatures) {
+    let pm_timer = fadt
+        .and_then(|fadt| Some((fadt.pm_timer_port()?, fadt.pm_timer_bits())))
+        .map(|(port, bits)| PM_TIMER.call_once(|| PmTimer::new(port, bits)));
+    let reference = match pm_timer {
+        Some(pm_timer) => Reference::PmTimer(pm_timer),
+        None => Reference::Pit,
+    };
+
+    let (tsc_frequency, lapic_timer_frequency) = calibrate(&reference);
+    let tsc = TSC.call_once(|| Tsc {
+        frequency: tsc_frequency,
+    });
+    LAPIC_TIMER_FREQUENCY.call_once(|| lapic_timer_frequency);
+
+    // The TSC is the cheapest to read, but without the invariant TSC its rate
+    // changes with the power state of the cpu
+    let source: &'static dyn ClockSource = match pm_timer {
+        Some(pm_timer) if !cpu_features.invariant_tsc => pm_timer,
+        _ => tsc,
+    };
+    CLOCK.call_once(|| Clock {
+        source,
+        start: source.read(),
+    });
+
+    sprintln!(
+        "Calibrated against {}: tsc {} Hz, lapic timer {} Hz, clock source {}",
+        reference.name(),
+        tsc_frequency,
+        lapic_timer_frequency,
+        source.name()
+    );
+}
+
+// Counts how far the TSC and the local apic timer get while the reference
+// waits, returns their frequencies
+fn calibrate(reference: &Reference) -> (u64, u64) {
+    LAPIC.with(|lapic| {
+        lapic.write_timer_lvt(LAPIC_TIMER_MASKED);
+        lapic.write_divide_configuration(LAPIC_TIMER_DIVIDE_BY_16);
+
+        let tsc_start = rdtsc();
+        lapic.write_initial_count(u32::MAX);
+        reference.delay_us(CALIBRATION_US);
+        let lapic_ticks = u32::MAX - lapic.read_current_count();
+        let tsc_ticks = rdtsc() - tsc_start;
+        lapic.write_initial_count(0);
+
+        (
+            tsc_ticks * 1_000_000 / CALIBRATION_US,
+            lapic_ticks as u64 * 1_000_000 / CALIBRATION_US,
+        )
+    })
+}
+
+/// Makes the local apic timer of the current cpu raise `vector` `frequency`
+/// times per second.
+pub fn start_tick(vector: u8, frequency: u32) {
+    let lapic_timer_frequency = *LAPIC_TIMER_FREQUENCY
+        .get()
+        .expect("time::init has to run first");
+    let initial_count = (lapic_timer_frequency / frequency as u64).clamp(1, u32::MAX as u64);
+    LAPIC.with(|lapic| {
+        lapic.write_divide_configuration(LAPIC_TIMER_DIVIDE_BY_16);
+        lapic.write_timer_lvt(LAPIC_TIMER_PERIODIC | vector as u32);
+        lapic.write_initial_count(initial_count as u32);
+    });
+}
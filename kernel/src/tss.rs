@@ -0,0 +1,33 @@
+use x86_64::tss::TaskStateSegment;
+
+use crate::per_cpu;
+use crate::VMALLOC;
+
+// Interrupt stack table indices, exceptions that can happen while the
+// current stack is unusable get a stack of their own
+pub const DOUBLE_FAULT_IST: u8 = 1;
+pub const NMI_IST: u8 = 2;
+pub const MACHINE_CHECK_IST: u8 = 3;
+
+const IST_STACK_PAGES: usize = 4;
+
+per_cpu! {
+    static TSS: TaskStateSegment = TaskStateSegment::new();
+}
+
+/// Allocates the interrupt stacks of the current cpu and returns its TSS, to
+/// be loaded once it is in the GDT.
+pub fn init() -> &'static TaskStateSegment {
+    let tss = TSS.with(|tss| {
+        for ist in [DOUBLE_FAULT_IST, NMI_IST, MACHINE_CHECK_IST] {
+            // The guard page turns an overflow into a page fault, which the
+            // double fault handler then reports from its own stack
+            let stack = VMALLOC.allocate(IST_STACK_PAGES, 1, true).unwrap();
+            tss.interrupt_stack_table[ist as usize - 1] = stack.end().as_u64();
+        }
+        tss as *const TaskStateSegment
+    });
+
+    // Only the cpu itself touches its TSS, and only here
+    unsafe { &*tss }
+}
@@ -0,0 +1,309 @@
+use common::addr::PhysAddr;
+use common::addr::VirtAddr;
+use common::frame::FrameAllocator;
+use sync::SpinLock;
+use x86_64::paging::MappedPageTable;
+use x86_64::paging::PageTableFrameOffsetMapper;
+use x86_64::tlb;
+
+use crate::smp;
+use crate::sprintln;
+
+const PAGE_SIZE: u64 = 4096;
+
+// The higher half direct map starts at 0xffff_8000_0000_0000 and the kernel
+// image lives in the last 2 GiB, so this range is free for anything else
+pub const VMALLOC_START: u64 = 0xffff_c000_0000_0000;
+pub const VMALLOC_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+
+// Adjacent free ranges are merged, so this only runs out when the virtual
+// address space is badly fragmented
+const MAX_FREE_RANGES: usize = 128;
+
+// Unmapped frames are only freed once no cpu can reach them through a stale
+// TLB entry, which takes a shootdown per batch of pages
+const RELEASE_BATCH: usize = 16;
+
+#[derive(Debug)]
+pub enum VmallocError {
+    NotInitialized,
+    NoVirtualSpace,
+    NoFrames,
+    Map,
+}
+
+/// A virtually contiguous, page granular region of the kernel address space,
+/// backed by frames that need not be physically contiguous.
+#[derive(Debug)]
+pub struct VirtualArea {
+    start: VirtAddr,
+    pages: usize,
+    // Unmapped pages on each side of the area, so that running off either
+    // end faults instead of silently corrupting a neighbour
+    guard_pages: usize,
+    // Device memory is mapped to frames that don't come from the frame allocator
+    owns_frames: bool,
+}
+
+impl VirtualArea {
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn end(&self) -> VirtAddr {
+        VirtAddr::new(self.start.as_u64() + self.size() as u64)
+    }
+
+    pub fn size(&self) -> usize {
+        self.pages * PAGE_SIZE as usize
+    }
+
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    pub fn as_ptr_mut<T>(&self) -> *mut T {
+        self.start.as_u64() as *mut T
+    }
+}
+
+// Hands out regions of [VMALLOC_START, VMALLOC_START + VMALLOC_SIZE) and maps
+// them to frames from the frame allocator
+pub struct Vmalloc<F: FrameAllocator> {
+    inner: SpinLock<Option<VmallocInner>>,
+    frame_allocator: F,
+}
+
+struct VmallocInner {
+    page_table: MappedPageTable<'static, PageTableFrameOffsetMapper>,
+    free: FreeRanges,
+}
+
+impl<F: FrameAllocator> Vmalloc<F> {
+    pub const fn new(frame_allocator: F) -> Self {
+        Self {
+            inner: SpinLock::new(None),
+            frame_allocator,
+        }
+    }
+
+    /// Takes over the kernel page table, all mapping and unmapping has to go
+    /// through this allocator afterwards.
+    pub fn init(&self, page_table: MappedPageTable<'static, PageTableFrameOffsetMapper>) {
+        let mut free = FreeRanges::new();
+        free.insert(VMALLOC_START, VMALLOC_SIZE / PAGE_SIZE);
+        *self.inner.lock() = Some(VmallocInner { page_table, free });
+    }
+
+    /// Allocates `pages` mapped pages, surrounded by `guard_pages` unmapped
+    /// pages on each side.
+    pub fn allocate(
+        &self,
+        pages: usize,
+        guard_pages: usize,
+        writable: bool,
+    ) -> Result<VirtualArea, VmallocError> {
+        let mut guard = self.inner.lock();
+        let inner = guard.as_mut().ok_or(VmallocError::NotInitialized)?;
+
+        let total_pages = (pages + 2 * guard_pages) as u64;
+        let base = inner
+            .free
+            .allocate(total_pages)
+            .ok_or(VmallocError::NoVirtualSpace)?;
+        let area = VirtualArea {
+            start: VirtAddr::new(base + guard_pages as u64 * PAGE_SIZE),
+            pages,
+            guard_pages,
+            owns_frames: true,
+        };
+
+        for i in 0..pages {
+            let page = VirtAddr::new(area.start.as_u64() + i as u64 * PAGE_SIZE);
+            let result = match self.frame_allocator.allocate_frame() {
+                Ok(frame) => inner
+                    .page_table
+                    .map(page, frame, &self.frame_allocator, writable)
+                    .map_err(|_| {
+                        self.frame_allocator.deallocate_frame(frame).unwrap();
+                        VmallocError::Map
+                    }),
+                Err(_) => Err(VmallocError::NoFrames),
+            };
+
+            if let Err(error) = result {
+                // Roll back the pages that did get mapped
+                drop(guard);
+                self.release(&area, i);
+                return Err(error);
+            }
+        }
+
+        Ok(area)
+    }
+
+    /// Maps `pages` pages of device memory starting at `frame`, uncached.
+    pub fn map_mmio(&self, frame: PhysAddr, pages: usize) -> Result<VirtualArea, VmallocError> {
+        let mut guard = self.inner.lock();
+        let inner = guard.as_mut().ok_or(VmallocError::NotInitialized)?;
+
+        let base = inner
+            .free
+            .allocate(pages as u64)
+            .ok_or(VmallocError::NoVirtualSpace)?;
+        let area = VirtualArea {
+            start: VirtAddr::new(base),
+            pages,
+            guard_pages: 0,
+            owns_frames: false,
+        };
+
+        for i in 0..pages {
+            let offset = i as u64 * PAGE_SIZE;
+            let result = inner.page_table.map_mmio(
+                VirtAddr::new(base + offset),
+                frame.add(offset),
+                &self.frame_allocator,
+            );
+            if result.is_err() {
+                drop(guard);
+                self.release(&area, i);
+                return Err(VmallocError::Map);
+            }
+        }
+
+        Ok(area)
+    }
+
+    /// Unmaps the area on all cpus and returns its frames to the frame
+    /// allocator.
+    ///
+    /// # Safety
+    ///
+    /// Nothing may reference memory in the area after this call.
+    pub unsafe fn deallocate(&self, area: VirtualArea) {
+        self.release(&area, area.pages);
+    }
+
+    // Unmaps the first `mapped_pages` pages of `area` a batch at a time and
+    // gives the virtual range back along with the last batch. The shootdown
+    // runs without the lock, which disables interrupts, so that a cpu spinning
+    // on it can still answer. Frames are only freed after the shootdown
+    fn release(&self, area: &VirtualArea, mapped_pages: usize) {
+        let mut frames = [PhysAddr::new(0); RELEASE_BATCH];
+        let batches = mapped_pages.div_ceil(RELEASE_BATCH).max(1);
+        for batch in 0..batches {
+            let batch_start = batch * RELEASE_BATCH;
+            let batch_pages = RELEASE_BATCH.min(mapped_pages - batch_start);
+            let batch_addr = area.start.as_u64() + batch_start as u64 * PAGE_SIZE;
+            {
+                let mut inner = self.inner.lock();
+                let inner = inner.as_mut().unwrap();
+                for (i, frame) in frames[..batch_pages].iter_mut().enumerate() {
+                    let page = VirtAddr::new(batch_addr + i as u64 * PAGE_SIZE);
+                    *frame = inner.page_table.unmap(page, &self.frame_allocator).unwrap();
+                    tlb::flush(page);
+                }
+
+                if batch == batches - 1 {
+                    let guard_size = area.guard_pages as u64 * PAGE_SIZE;
+                    inner.free.insert(
+                        area.start.as_u64() - guard_size,
+                        (area.pages + 2 * area.guard_pages) as u64,
+                    );
+                }
+            }
+
+            if batch_pages == 0 {
+                continue;
+            }
+
+            smp::flush_tlb_others(VirtAddr::new(batch_addr), batch_pages);
+            if area.owns_frames {
+                for frame in &frames[..batch_pages] {
+                    self.frame_allocator.deallocate_frame(*frame).unwrap();
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct FreeRange {
+    start: u64,
+    pages: u64,
+}
+
+impl FreeRange {
+    fn end(&self) -> u64 {
+        self.start + self.pages * PAGE_SIZE
+    }
+}
+
+// Free ranges sorted by address, allocated from first fit
+struct FreeRanges {
+    ranges: [FreeRange; MAX_FREE_RANGES],
+    len: usize,
+}
+
+impl FreeRanges {
+    const fn new() -> Self {
+        Self {
+            ranges: [FreeRange { start: 0, pages: 0 }; MAX_FREE_RANGES],
+            len: 0,
+        }
+    }
+
+    fn allocate(&mut self, pages: u64) -> Option<u64> {
+        let index = self.ranges[..self.len]
+            .iter()
+            .position(|range| range.pages >= pages)?;
+        let range = &mut self.ranges[index];
+        let start = range.start;
+        range.start += pages * PAGE_SIZE;
+        range.pages -= pages;
+        if range.pages == 0 {
+            self.ranges.copy_within(index + 1..self.len, index);
+            self.len -= 1;
+        }
+
+        Some(start)
+    }
+
+    fn insert(&mut self, start: u64, pages: u64) {
+        let index = self.ranges[..self.len]
+            .iter()
+            .position(|range| range.start > start)
+            .unwrap_or(self.len);
+        let end = start + pages * PAGE_SIZE;
+        let merges_prev = index > 0 && self.ranges[index - 1].end() == start;
+        let merges_next = index < self.len && self.ranges[index].start == end;
+
+        match (merges_prev, merges_next) {
+            (true, true) => {
+                self.ranges[index - 1].pages += pages + self.ranges[index].pages;
+                self.ranges.copy_within(index + 1..self.len, index);
+                self.len -= 1;
+            }
+            (true, false) => self.ranges[index - 1].pages += pages,
+            (false, true) => {
+                self.ranges[index].start = start;
+                self.ranges[index].pages += pages;
+            }
+            (false, false) => {
+                if self.len == MAX_FREE_RANGES {
+                    sprintln!(
+                        "vmalloc: too many free ranges, leaking {:#x} ({} pages)",
+                        start,
+                        pages
+                    );
+                    return;
+                }
+
+                self.ranges.copy_within(index..self.len, index + 1);
+                self.ranges[index] = FreeRange { start, pages };
+                self.len += 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::Deref;
+use core::ops::DerefMut;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+use crate::sched::WaitQueue;
+
+// Blocking primitives, built on `WaitQueue`. Waiting puts the thread to sleep
+// until another thread or an interrupt handler wakes it, so they can only be
+// waited on from threads. Everything that wakes waiters is allowed from
+// interrupt handlers, except unlocking a `Mutex`, which is only ever held by
+// threads. Not all of them have users outside the tests and the self test
+// yet.
+
+/// A mutual exclusion lock that blocks waiting threads instead of spinning,
+/// for critical sections that can sleep or take long.
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+impl<T: fmt::Debug> fmt::Debug for Mutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_tuple("Mutex").field(&*guard).finish(),
+            None => f.debug_tuple("Mutex").field(&"<locked>").finish(),
+        }
+    }
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.waiters.wait_until(|| self.acquire());
+        MutexGuard { mutex: self }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.acquire().then_some(MutexGuard { mutex: self })
+    }
+
+    #[allow(dead_code)]
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    fn acquire(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.wake_one();
+    }
+}
+
+/// A counting semaphore, `acquire` blocks while the count is zero.
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Self {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Takes one from the count, waiting until it is non-zero.
+    pub fn acquire(&self) {
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.count
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    /// Adds one to the count and wakes a waiter to take it.
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+}
+
+/// Waits for a condition protected by a `Mutex` to change.
+pub struct Condvar {
+    // Bumped by every notification, waiters sleep until it changes
+    generation: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            generation: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlocks the mutex of `guard` and waits for a notification, then locks
+    /// it again. Notifications between the unlock and going to sleep aren't
+    /// lost. Like with any condition variable the condition has to be checked
+    /// again after waking.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        let generation = self.generation.load(Ordering::Acquire);
+        drop(guard);
+        self.waiters
+            .wait_until(|| self.generation.load(Ordering::Acquire) != generation);
+        mutex.lock()
+    }
+
+    /// Waits until `condition` returns false, see `wait`.
+    pub fn wait_while<'a, T>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: impl FnMut(&mut T) -> bool,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    #[allow(dead_code)]
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.wake_all();
+    }
+}
+
+/// A one-shot event, once set every waiter, past and future, continues.
+#[allow(dead_code)]
+pub struct Event {
+    set: AtomicBool,
+    waiters: WaitQueue,
+}
+
+#[allow(dead_code)]
+impl Event {
+    pub const fn new() -> Self {
+        Self {
+            set: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    pub fn wait(&self) {
+        self.waiters.wait_until(|| self.is_set());
+    }
+
+    pub fn set(&self) {
+        self.set.store(true, Ordering::Release);
+        self.waiters.wake_all();
+    }
+
+    pub fn is_set(&self) -> bool {
+        self.set.load(Ordering::Acquire)
+    }
+}
+
+// Only what doesn't block, waiting needs the scheduler
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mutex_try_lock() {
+        let mutex = Mutex::new(1);
+        let mut guard = mutex.try_lock().unwrap();
+        assert!(mutex.try_lock().is_none());
+        *guard += 1;
+        drop(guard);
+        assert_eq!(*mutex.try_lock().unwrap(), 2);
+        assert_eq!(mutex.into_inner(), 2);
+    }
+
+    #[test]
+    fn semaphore_counts() {
+        let semaphore = Semaphore::new(2);
+        assert!(semaphore.try_acquire());
+        assert!(semaphore.try_acquire());
+        assert!(!semaphore.try_acquire());
+        semaphore.release();
+        assert!(semaphore.try_acquire());
+    }
+
+    #[test]
+    fn event_stays_set() {
+        let event = Event::new();
+        assert!(!event.is_set());
+        event.set();
+        event.set();
+        assert!(event.is_set());
+    }
+}
@@ -1,3 +1,4 @@
+use core::marker::PhantomData;
 use core::mem::size_of;
 
 #[derive(Clone, Copy, Debug)]
@@ -144,6 +145,35 @@ pub struct Fadt {
     pub hypervisor_vendor_id: u64,
 }
 
+impl AcpiTable for Fadt {
+    const SIGNATURE: [u8; 4] = *b"FACP";
+
+    fn header(&self) -> &DefinitionHeader {
+        &self.header
+    }
+}
+
+impl Fadt {
+    // TMR_VAL_EXT, the PM timer counts with 32 instead of 24 bits
+    const TIMER_VALUE_EXTENDED: u32 = 1 << 8;
+
+    /// The I/O port of the ACPI PM timer, `None` if there isn't one.
+    pub fn pm_timer_port(&self) -> Option<u16> {
+        match self.pm_timer_block {
+            0 => None,
+            port => Some(port as u16),
+        }
+    }
+
+    /// Bits of the PM timer counter, either 24 or 32.
+    pub fn pm_timer_bits(&self) -> u32 {
+        match self.flags & Self::TIMER_VALUE_EXTENDED {
+            0 => 24,
+            _ => 32,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 #[repr(C, packed)]
 pub struct GenericAddressStructure {
@@ -153,3 +183,377 @@ pub struct GenericAddressStructure {
     access_size: u8,
     address: u64,
 }
+
+/// Multiple APIC Description Table, describing the interrupt controllers and
+/// processors of the system.
+///
+/// The entries follow the table in memory, so a `Madt` is only useful as a
+/// reference to the table in place, not as a copy.
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct Madt {
+    pub header: DefinitionHeader,
+    pub local_apic_address: u32,
+    pub flags: u32,
+}
+
+impl AcpiTable for Madt {
+    const SIGNATURE: [u8; 4] = *b"APIC";
+
+    fn header(&self) -> &DefinitionHeader {
+        &self.header
+    }
+}
+
+impl Madt {
+    // The 8259 pics are installed and have to be masked when using the apics
+    pub const PCAT_COMPAT: u32 = 1 << 0;
+
+    pub fn entries(&self) -> MadtEntryIter<'_> {
+        MadtEntryIter {
+            ptr: (self as *const Self).cast::<u8>(),
+            offset: size_of::<Self>(),
+            len: self.header.length as usize,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Physical address of the local apic of every processor, taking a 64 bit
+    /// override into account.
+    pub fn local_apic_address(&self) -> u64 {
+        self.entries()
+            .find_map(|entry| match entry {
+                MadtEntry::LocalApicAddressOverride(entry) => Some(entry.address),
+                _ => None,
+            })
+            .unwrap_or(self.local_apic_address as u64)
+    }
+
+    /// Processors from both the local apic and local x2apic entries.
+    pub fn processors(&self) -> impl Iterator<Item = Processor> + '_ {
+        self.entries().filter_map(|entry| {
+            let (uid, apic_id, flags) = match entry {
+                MadtEntry::LocalApic(entry) => (
+                    entry.acpi_processor_uid as u32,
+                    entry.apic_id as u32,
+                    entry.flags,
+                ),
+                MadtEntry::LocalX2Apic(entry) => {
+                    (entry.acpi_processor_uid, entry.x2apic_id, entry.flags)
+                }
+                _ => return None,
+            };
+            Some(Processor {
+                uid,
+                apic_id,
+                enabled: flags & LocalApic::ENABLED != 0,
+                online_capable: flags & LocalApic::ONLINE_CAPABLE != 0,
+            })
+        })
+    }
+
+    pub fn io_apics(&self) -> impl Iterator<Item = IoApic> + '_ {
+        self.entries().filter_map(|entry| match entry {
+            MadtEntry::IoApic(entry) => Some(entry),
+            _ => None,
+        })
+    }
+
+    pub fn interrupt_source_overrides(&self) -> impl Iterator<Item = InterruptSourceOverride> + '_ {
+        self.entries().filter_map(|entry| match entry {
+            MadtEntry::InterruptSourceOverride(entry) => Some(entry),
+            _ => None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Processor {
+    pub uid: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+    // A disabled processor that can be brought online later
+    pub online_capable: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum MadtEntry {
+    LocalApic(LocalApic),
+    IoApic(IoApic),
+    InterruptSourceOverride(InterruptSourceOverride),
+    NmiSource(NmiSource),
+    LocalApicNmi(LocalApicNmi),
+    LocalApicAddressOverride(LocalApicAddressOverride),
+    LocalX2Apic(LocalX2Apic),
+    LocalX2ApicNmi(LocalX2ApicNmi),
+    Unknown { ty: u8, length: u8 },
+}
+
+pub struct MadtEntryIter<'a> {
+    ptr: *const u8,
+    offset: usize,
+    len: usize,
+    _marker: PhantomData<&'a Madt>,
+}
+
+impl Iterator for MadtEntryIter<'_> {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Every entry starts with its type and length
+        if self.offset + 2 > self.len {
+            return None;
+        }
+
+        let ptr = unsafe { self.ptr.add(self.offset) };
+        let ty = unsafe { ptr.read() };
+        let length = unsafe { ptr.add(1).read() };
+        if length < 2 || self.offset + length as usize > self.len {
+            return None;
+        }
+
+        self.offset += length as usize;
+        let entry = match ty {
+            0 => MadtEntry::LocalApic(unsafe { read_entry(ptr, length) }?),
+            1 => MadtEntry::IoApic(unsafe { read_entry(ptr, length) }?),
+            2 => MadtEntry::InterruptSourceOverride(unsafe { read_entry(ptr, length) }?),
+            3 => MadtEntry::NmiSource(unsafe { read_entry(ptr, length) }?),
+            4 => MadtEntry::LocalApicNmi(unsafe { read_entry(ptr, length) }?),
+            5 => MadtEntry::LocalApicAddressOverride(unsafe { read_entry(ptr, length) }?),
+            9 => MadtEntry::LocalX2Apic(unsafe { read_entry(ptr, length) }?),
+            10 => MadtEntry::LocalX2ApicNmi(unsafe { read_entry(ptr, length) }?),
+            _ => MadtEntry::Unknown { ty, length },
+        };
+        Some(entry)
+    }
+}
+
+/// Reads the body of an entry, following its type and length.
+///
+/// # Safety
+///
+/// `ptr` must point to an entry of `length` bytes.
+unsafe fn read_entry<T>(ptr: *const u8, length: u8) -> Option<T> {
+    if (length as usize) < 2 + size_of::<T>() {
+        return None;
+    }
+
+    Some(unsafe { ptr.add(2).cast::<T>().read_unaligned() })
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct LocalApic {
+    pub acpi_processor_uid: u8,
+    pub apic_id: u8,
+    pub flags: u32,
+}
+
+impl LocalApic {
+    pub const ENABLED: u32 = 1 << 0;
+    pub const ONLINE_CAPABLE: u32 = 1 << 1;
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct IoApic {
+    pub io_apic_id: u8,
+    reserved: u8,
+    pub address: u32,
+    pub global_system_interrupt_base: u32,
+}
+
+/// Maps an ISA interrupt to a global system interrupt that differs from the
+/// identity mapping, or has a non default polarity or trigger mode.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct InterruptSourceOverride {
+    // Always 0, meaning ISA
+    pub bus: u8,
+    pub source: u8,
+    pub global_system_interrupt: u32,
+    pub flags: u16,
+}
+
+impl InterruptSourceOverride {
+    pub fn polarity(&self) -> Polarity {
+        Polarity::from_flags(self.flags)
+    }
+
+    pub fn trigger_mode(&self) -> TriggerMode {
+        TriggerMode::from_flags(self.flags)
+    }
+}
+
+/// A global system interrupt that should be set up as a non-maskable interrupt.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct NmiSource {
+    pub flags: u16,
+    pub global_system_interrupt: u32,
+}
+
+impl NmiSource {
+    pub fn polarity(&self) -> Polarity {
+        Polarity::from_flags(self.flags)
+    }
+
+    pub fn trigger_mode(&self) -> TriggerMode {
+        TriggerMode::from_flags(self.flags)
+    }
+}
+
+/// Local apic interrupt input that an NMI is connected to.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct LocalApicNmi {
+    // 0xff means all processors
+    pub acpi_processor_uid: u8,
+    pub flags: u16,
+    pub lint: u8,
+}
+
+impl LocalApicNmi {
+    pub const ALL_PROCESSORS: u8 = 0xff;
+
+    pub fn polarity(&self) -> Polarity {
+        Polarity::from_flags(self.flags)
+    }
+
+    pub fn trigger_mode(&self) -> TriggerMode {
+        TriggerMode::from_flags(self.flags)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct LocalApicAddressOverride {
+    reserved: u16,
+    pub address: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct LocalX2Apic {
+    reserved: u16,
+    pub x2apic_id: u32,
+    pub flags: u32,
+    pub acpi_processor_uid: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed)]
+pub struct LocalX2ApicNmi {
+    pub flags: u16,
+    // 0xffff_ffff means all processors
+    pub acpi_processor_uid: u32,
+    pub lint: u8,
+    reserved: [u8; 3],
+}
+
+impl LocalX2ApicNmi {
+    pub const ALL_PROCESSORS: u32 = 0xffff_ffff;
+
+    pub fn polarity(&self) -> Polarity {
+        Polarity::from_flags(self.flags)
+    }
+
+    pub fn trigger_mode(&self) -> TriggerMode {
+        TriggerMode::from_flags(self.flags)
+    }
+}
+
+/// Polarity from the MPS INTI flags, bits 0:1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    // Conforms to the bus, active high for ISA
+    BusDefault,
+    ActiveHigh,
+    ActiveLow,
+    Reserved,
+}
+
+impl Polarity {
+    fn from_flags(flags: u16) -> Self {
+        match flags & 0b11 {
+            0b00 => Self::BusDefault,
+            0b01 => Self::ActiveHigh,
+            0b11 => Self::ActiveLow,
+            _ => Self::Reserved,
+        }
+    }
+}
+
+/// Trigger mode from the MPS INTI flags, bits 2:3.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TriggerMode {
+    // Conforms to the bus, edge triggered for ISA
+    BusDefault,
+    Edge,
+    Level,
+    Reserved,
+}
+
+impl TriggerMode {
+    fn from_flags(flags: u16) -> Self {
+        match (flags >> 2) & 0b11 {
+            0b00 => Self::BusDefault,
+            0b01 => Self::Edge,
+            0b11 => Self::Level,
+            _ => Self::Reserved,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_madt_entries() {
+        #[repr(C, align(8))]
+        struct Table([u8; 44 + 8 + 12 + 10 + 6 + 16]);
+
+        let mut table = Table([0; 44 + 8 + 12 + 10 + 6 + 16]);
+        let bytes = &mut table.0;
+        bytes[0..4].copy_from_slice(b"APIC");
+        let length = bytes.len() as u32;
+        bytes[4..8].copy_from_slice(&length.to_le_bytes());
+        bytes[36..40].copy_from_slice(&0xfee0_0000u32.to_le_bytes());
+        let entries = [
+            &[0, 8, 0, 0, 1, 0, 0, 0][..],
+            &[1, 12, 2, 0, 0, 0, 0xc0, 0xfe, 0, 0, 0, 0],
+            &[2, 10, 0, 0, 2, 0, 0, 0, 0b1111, 0],
+            &[4, 6, 0xff, 0b0101, 0, 1],
+            &[9, 16, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0],
+        ];
+        let mut offset = 44;
+        for entry in entries {
+            bytes[offset..offset + entry.len()].copy_from_slice(entry);
+            offset += entry.len();
+        }
+
+        let madt = unsafe { &*(table.0.as_ptr() as *const Madt) };
+        assert_eq!(madt.entries().count(), 5);
+        assert_eq!(madt.local_apic_address(), 0xfee0_0000);
+
+        let mut processors = madt.processors();
+        let bsp = processors.next().unwrap();
+        assert_eq!(bsp.apic_id, 0);
+        assert!(bsp.enabled);
+        let x2apic = processors.next().unwrap();
+        assert_eq!(x2apic.apic_id, 0x100);
+        assert_eq!(x2apic.uid, 7);
+        assert!(!x2apic.enabled);
+        assert!(processors.next().is_none());
+
+        let io_apic = madt.io_apics().next().unwrap();
+        assert_eq!({ io_apic.address }, 0xfec0_0000);
+
+        let iso = madt.interrupt_source_overrides().next().unwrap();
+        assert_eq!(iso.source, 0);
+        assert_eq!({ iso.global_system_interrupt }, 2);
+        assert_eq!(iso.polarity(), Polarity::ActiveLow);
+        assert_eq!(iso.trigger_mode(), TriggerMode::Level);
+    }
+}
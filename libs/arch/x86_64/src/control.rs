@@ -1,3 +1,5 @@
+use common::addr::PhysAddr;
+
 #[derive(Debug)]
 pub struct Cr0 {
     // 0 	PE 	Protected Mode Enable
@@ -29,11 +31,42 @@ pub struct Cr0 {
 }
 
 impl Cr0 {
+    const KNOWN_BITS: u64 = 0xe005_003f;
+
     pub fn read() -> Self {
+        Self::from_bits(Self::read_raw())
+    }
+
+    pub fn read_raw() -> u64 {
         let cr0: u64;
         unsafe {
             core::arch::asm!("mov {}, cr0", out(reg) cr0);
         }
+        cr0
+    }
+
+    /// # Safety
+    ///
+    /// Changing CR0 can disable paging, caching or protection the kernel
+    /// relies on.
+    pub unsafe fn write_raw(value: u64) {
+        unsafe { core::arch::asm!("mov cr0, {}", in(reg) value, options(nostack, preserves_flags)) }
+    }
+
+    /// Reads CR0, lets `f` change it and writes it back, bits that aren't
+    /// known are kept as they are.
+    ///
+    /// # Safety
+    ///
+    /// See `write_raw`.
+    pub unsafe fn update(f: impl FnOnce(&mut Self)) {
+        let raw = Self::read_raw();
+        let mut cr0 = Self::from_bits(raw);
+        f(&mut cr0);
+        unsafe { Self::write_raw((raw & !Self::KNOWN_BITS) | cr0.bits()) }
+    }
+
+    fn from_bits(cr0: u64) -> Self {
         Self {
             pe: cr0 & (1 << 0) != 0,
             mp: cr0 & (1 << 1) != 0,
@@ -48,6 +81,50 @@ impl Cr0 {
             pg: cr0 & (1 << 31) != 0,
         }
     }
+
+    pub fn bits(&self) -> u64 {
+        self.pe as u64
+            | (self.mp as u64) << 1
+            | (self.em as u64) << 2
+            | (self.ts as u64) << 3
+            | (self.et as u64) << 4
+            | (self.ne as u64) << 5
+            | (self.wp as u64) << 16
+            | (self.am as u64) << 18
+            | (self.nw as u64) << 29
+            | (self.cd as u64) << 30
+            | (self.pg as u64) << 31
+    }
+
+    /// Write protect, supervisor writes to read only pages fault as well.
+    pub fn write_protect(&self) -> bool {
+        self.wp
+    }
+
+    pub fn set_write_protect(&mut self, enable: bool) -> &mut Self {
+        self.wp = enable;
+        self
+    }
+
+    /// Cache disable.
+    pub fn cache_disable(&self) -> bool {
+        self.cd
+    }
+
+    pub fn set_cache_disable(&mut self, disable: bool) -> &mut Self {
+        self.cd = disable;
+        self
+    }
+
+    /// Not write-through, only meaningful together with cache disable.
+    pub fn not_write_through(&self) -> bool {
+        self.nw
+    }
+
+    pub fn set_not_write_through(&mut self, enable: bool) -> &mut Self {
+        self.nw = enable;
+        self
+    }
 }
 
 #[derive(Debug)]
@@ -55,11 +132,15 @@ pub struct Cr2(u64);
 
 impl Cr2 {
     pub fn read() -> Self {
+        Self(Self::read_raw())
+    }
+
+    pub fn read_raw() -> u64 {
         let cr2: u64;
         unsafe {
             core::arch::asm!("mov {}, cr2", out(reg) cr2);
         }
-        Self(cr2)
+        cr2
     }
 }
 
@@ -69,7 +150,7 @@ pub struct Cr3 {
     pwt: bool,
     // 5 	PCD 	Page-Level Cache Disable, when CR4.PCIDE = 0
     pcd: bool,
-    //  0-11 	PCID, when CR4.PCIDE = 1
+    // 0-11 	PCID, when CR4.PCIDE = 1
     pcid: u16,
     // 12-63 	Physical Base Address of the PML4
     pub pba_pml4: u64,
@@ -77,21 +158,40 @@ pub struct Cr3 {
 
 impl Cr3 {
     pub fn read() -> Self {
-        let cr3: u64;
-        unsafe {
-            core::arch::asm!("mov {}, cr3", out(reg) cr3);
-        };
+        let cr3 = Self::read_raw();
         Self {
             pwt: cr3 & (1 << 3) != 0,
             pcd: cr3 & (1 << 5) != 0,
-            pcid: (cr3 & 0b111_1111_1111) as u16,
+            pcid: (cr3 & 0xfff) as u16,
             pba_pml4: (cr3 & !0xFFF),
         }
     }
 
+    pub fn read_raw() -> u64 {
+        let cr3: u64;
+        unsafe {
+            core::arch::asm!("mov {}, cr3", out(reg) cr3);
+        }
+        cr3
+    }
+
+    /// Switches to the PML4 at `pml4`, tagging the translations with `pcid`.
+    /// The PCID has to be 0 unless CR4.PCIDE is set.
+    ///
+    /// # Safety
+    ///
+    /// `pml4` must be a valid page table that maps the running code, its
+    /// stack and everything else the kernel is about to touch.
     #[inline(always)]
-    pub fn write(val: u64) {
-        unsafe { core::arch::asm!("mov cr3, {}", in(reg) val) }
+    pub unsafe fn write(pml4: PhysAddr, pcid: u16) {
+        assert!(pml4.as_u64() & 0xfff == 0, "unaligned pml4");
+        assert!(pcid < 4096, "pcid out of range");
+        let value = pml4.as_u64() | pcid as u64;
+        unsafe { core::arch::asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags)) }
+    }
+
+    pub fn pcid(&self) -> u16 {
+        self.pcid
     }
 }
 
@@ -120,8 +220,9 @@ pub struct Cr4 {
     // 10 	OSXMMEXCPT 	OS Support for unmasked simd floating point exceptions
     osxmmexcpt: bool,
     // 11 	UMIP 	User-Mode Instruction Prevention (SGDT, SIDT, SLDT, SMSW, and STR are disabled in user mode)
-    uimp: bool,
-    // 12 	0 	Reserved
+    umip: bool,
+    // 12 	LA57 	57-bit linear addresses, 5-level paging
+    la57: bool,
     // 13 	VMXE 	Virtual Machine Extensions Enable
     vmxe: bool,
     // 14 	SMXE 	Safer Mode Extensions Enable
@@ -148,11 +249,42 @@ pub struct Cr4 {
 }
 
 impl Cr4 {
+    const KNOWN_BITS: u64 = 0x01f7_7fff;
+
     pub fn read() -> Self {
+        Self::from_bits(Self::read_raw())
+    }
+
+    pub fn read_raw() -> u64 {
         let cr4: u64;
         unsafe {
             core::arch::asm!("mov {}, cr4", out(reg) cr4);
-        };
+        }
+        cr4
+    }
+
+    /// # Safety
+    ///
+    /// Changing CR4 can turn off features the kernel relies on, and setting
+    /// bits the cpu doesn't support raises a #GP.
+    pub unsafe fn write_raw(value: u64) {
+        unsafe { core::arch::asm!("mov cr4, {}", in(reg) value, options(nostack, preserves_flags)) }
+    }
+
+    /// Reads CR4, lets `f` change it and writes it back, bits that aren't
+    /// known are kept as they are.
+    ///
+    /// # Safety
+    ///
+    /// See `write_raw`.
+    pub unsafe fn update(f: impl FnOnce(&mut Self)) {
+        let raw = Self::read_raw();
+        let mut cr4 = Self::from_bits(raw);
+        f(&mut cr4);
+        unsafe { Self::write_raw((raw & !Self::KNOWN_BITS) | cr4.bits()) }
+    }
+
+    fn from_bits(cr4: u64) -> Self {
         Self {
             vme: cr4 & (1 << 0) != 0,
             pvi: cr4 & (1 << 1) != 0,
@@ -165,7 +297,8 @@ impl Cr4 {
             pce: cr4 & (1 << 8) != 0,
             osfxsr: cr4 & (1 << 9) != 0,
             osxmmexcpt: cr4 & (1 << 10) != 0,
-            uimp: cr4 & (1 << 11) != 0,
+            umip: cr4 & (1 << 11) != 0,
+            la57: cr4 & (1 << 12) != 0,
             vmxe: cr4 & (1 << 13) != 0,
             smxe: cr4 & (1 << 14) != 0,
             fsgsbase: cr4 & (1 << 16) != 0,
@@ -178,4 +311,112 @@ impl Cr4 {
             pks: cr4 & (1 << 24) != 0,
         }
     }
+
+    pub fn bits(&self) -> u64 {
+        self.vme as u64
+            | (self.pvi as u64) << 1
+            | (self.tsd as u64) << 2
+            | (self.de as u64) << 3
+            | (self.pse as u64) << 4
+            | (self.pae as u64) << 5
+            | (self.mce as u64) << 6
+            | (self.pge as u64) << 7
+            | (self.pce as u64) << 8
+            | (self.osfxsr as u64) << 9
+            | (self.osxmmexcpt as u64) << 10
+            | (self.umip as u64) << 11
+            | (self.la57 as u64) << 12
+            | (self.vmxe as u64) << 13
+            | (self.smxe as u64) << 14
+            | (self.fsgsbase as u64) << 16
+            | (self.pcide as u64) << 17
+            | (self.osxsave as u64) << 18
+            | (self.smep as u64) << 20
+            | (self.smap as u64) << 21
+            | (self.pke as u64) << 22
+            | (self.cet as u64) << 23
+            | (self.pks as u64) << 24
+    }
+
+    /// Page global enable, global pages survive CR3 writes.
+    pub fn page_global(&self) -> bool {
+        self.pge
+    }
+
+    pub fn set_page_global(&mut self, enable: bool) -> &mut Self {
+        self.pge = enable;
+        self
+    }
+
+    /// Process context identifiers in CR3, can only be set while the PCID in
+    /// CR3 is 0.
+    pub fn pcid(&self) -> bool {
+        self.pcide
+    }
+
+    pub fn set_pcid(&mut self, enable: bool) -> &mut Self {
+        self.pcide = enable;
+        self
+    }
+
+    /// `fxsave` and `fxrstor` save the SSE state, required for SSE.
+    pub fn osfxsr(&self) -> bool {
+        self.osfxsr
+    }
+
+    pub fn set_osfxsr(&mut self, enable: bool) -> &mut Self {
+        self.osfxsr = enable;
+        self
+    }
+
+    /// `xsave` and `xgetbv` are enabled, required for AVX.
+    pub fn osxsave(&self) -> bool {
+        self.osxsave
+    }
+
+    pub fn set_osxsave(&mut self, enable: bool) -> &mut Self {
+        self.osxsave = enable;
+        self
+    }
+
+    /// Supervisor mode execution prevention, fetching from user pages faults.
+    pub fn smep(&self) -> bool {
+        self.smep
+    }
+
+    pub fn set_smep(&mut self, enable: bool) -> &mut Self {
+        self.smep = enable;
+        self
+    }
+
+    /// Supervisor mode access prevention, accessing user pages faults unless
+    /// RFLAGS.AC is set.
+    pub fn smap(&self) -> bool {
+        self.smap
+    }
+
+    pub fn set_smap(&mut self, enable: bool) -> &mut Self {
+        self.smap = enable;
+        self
+    }
+
+    /// User mode instruction prevention.
+    pub fn umip(&self) -> bool {
+        self.umip
+    }
+
+    pub fn set_umip(&mut self, enable: bool) -> &mut Self {
+        self.umip = enable;
+        self
+    }
+
+    /// 5-level paging, can only be changed with paging disabled.
+    pub fn la57(&self) -> bool {
+        self.la57
+    }
+
+    pub fn set_la57(&mut self, enable: bool) -> &mut Self {
+        self.la57 = enable;
+        self
+    }
 }
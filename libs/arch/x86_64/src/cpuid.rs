@@ -0,0 +1,457 @@
+use core::arch::x86_64::__cpuid_count;
+pub use core::arch::x86_64::CpuidResult;
+
+const LEAF_VENDOR: u32 = 0x0000_0000;
+const LEAF_FEATURES: u32 = 0x0000_0001;
+const LEAF_CACHE_PARAMETERS: u32 = 0x0000_0004;
+const LEAF_EXTENDED_FEATURES: u32 = 0x0000_0007;
+const LEAF_TOPOLOGY: u32 = 0x0000_000b;
+const LEAF_EXTENDED_MAX: u32 = 0x8000_0000;
+const LEAF_EXTENDED_PROCESSOR: u32 = 0x8000_0001;
+const LEAF_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const LEAF_AMD_CACHE_PARAMETERS: u32 = 0x8000_001d;
+
+/// Caches listed in `CpuFeatures`, more than any current cpu has.
+pub const MAX_CACHES: usize = 8;
+
+/// Executes `cpuid` for `leaf` and `subleaf`, leaves without sub-leaves
+/// ignore the latter.
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    __cpuid_count(leaf, subleaf)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Vendor {
+    Intel,
+    Amd,
+    Other([u8; 12]),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheKind {
+    Data,
+    Instruction,
+    Unified,
+}
+
+/// A cache described by the deterministic cache parameter leaf.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cache {
+    pub level: u8,
+    pub kind: CacheKind,
+    pub line_size: u32,
+    pub partitions: u32,
+    pub ways: u32,
+    pub sets: u32,
+    /// Maximum number of logical processors sharing the cache.
+    pub shared_by: u32,
+}
+
+impl Cache {
+    /// Size in bytes.
+    pub fn size(&self) -> u64 {
+        self.line_size as u64 * self.partitions as u64 * self.ways as u64 * self.sets as u64
+    }
+}
+
+/// How the x2APIC id of the current cpu splits into thread, core and package.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Topology {
+    pub x2apic_id: u32,
+    /// Bits of the id that select the thread within a core.
+    pub smt_shift: u8,
+    /// Bits of the id that select the thread within a package.
+    pub package_shift: u8,
+}
+
+impl Topology {
+    pub fn thread_id(&self) -> u32 {
+        self.x2apic_id & low_bits(self.smt_shift)
+    }
+
+    pub fn core_id(&self) -> u32 {
+        (self.x2apic_id & low_bits(self.package_shift))
+            .checked_shr(self.smt_shift as u32)
+            .unwrap_or(0)
+    }
+
+    pub fn package_id(&self) -> u32 {
+        self.x2apic_id
+            .checked_shr(self.package_shift as u32)
+            .unwrap_or(0)
+    }
+}
+
+// Mask of the lowest `count` bits, all of them for 32 and more
+fn low_bits(count: u8) -> u32 {
+    1u32.checked_shl(count as u32).unwrap_or(0).wrapping_sub(1)
+}
+
+/// What the current cpu supports, as reported by `cpuid`.
+#[derive(Clone, Copy, Debug)]
+pub struct CpuFeatures {
+    pub vendor: Vendor,
+    pub max_leaf: u32,
+    pub max_extended_leaf: u32,
+    /// Display family, with the extended family added when it applies.
+    pub family: u16,
+    /// Display model, with the extended model added when it applies.
+    pub model: u8,
+    pub stepping: u8,
+    /// APIC id of the current cpu, as assigned at reset.
+    pub initial_apic_id: u8,
+    pub clflush_line_size: u16,
+
+    // Leaf 0x1
+    pub sse: bool,
+    pub sse2: bool,
+    pub sse3: bool,
+    pub ssse3: bool,
+    pub sse4_1: bool,
+    pub sse4_2: bool,
+    pub avx: bool,
+    pub fxsr: bool,
+    pub xsave: bool,
+    pub osxsave: bool,
+    pub apic: bool,
+    pub x2apic: bool,
+    pub tsc: bool,
+    pub tsc_deadline: bool,
+    pub msr: bool,
+    pub pae: bool,
+    pub pge: bool,
+    pub pat: bool,
+    pub pcid: bool,
+    pub rdrand: bool,
+    pub hypervisor: bool,
+
+    // Leaf 0x7
+    pub fsgsbase: bool,
+    pub avx2: bool,
+    pub smep: bool,
+    pub smap: bool,
+    pub invpcid: bool,
+    pub rdseed: bool,
+    pub umip: bool,
+    pub la57: bool,
+
+    // Leaves 0x8000_0001 and 0x8000_0007
+    pub syscall: bool,
+    pub nx: bool,
+    pub pages_1gb: bool,
+    pub rdtscp: bool,
+    pub long_mode: bool,
+    pub invariant_tsc: bool,
+
+    caches: [Option<Cache>; MAX_CACHES],
+    topology: Option<Topology>,
+}
+
+impl CpuFeatures {
+    pub fn detect() -> Self {
+        Self::from_cpuid(cpuid)
+    }
+
+    /// Decodes the leaves returned by `cpuid`, which is given the leaf and
+    /// sub-leaf like the function of the same name.
+    pub fn from_cpuid(cpuid: impl Fn(u32, u32) -> CpuidResult) -> Self {
+        let vendor_leaf = cpuid(LEAF_VENDOR, 0);
+        let max_leaf = vendor_leaf.eax;
+        let mut vendor = [0; 12];
+        vendor[0..4].copy_from_slice(&vendor_leaf.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&vendor_leaf.edx.to_le_bytes());
+        vendor[8..12].copy_from_slice(&vendor_leaf.ecx.to_le_bytes());
+        let vendor = match &vendor {
+            b"GenuineIntel" => Vendor::Intel,
+            b"AuthenticAMD" => Vendor::Amd,
+            _ => Vendor::Other(vendor),
+        };
+
+        let max_extended_leaf = cpuid(LEAF_EXTENDED_MAX, 0).eax;
+        let leaf = |leaf: u32| match leaf {
+            _ if leaf < LEAF_EXTENDED_MAX && leaf <= max_leaf => cpuid(leaf, 0),
+            _ if leaf >= LEAF_EXTENDED_MAX && leaf <= max_extended_leaf => cpuid(leaf, 0),
+            _ => CpuidResult {
+                eax: 0,
+                ebx: 0,
+                ecx: 0,
+                edx: 0,
+            },
+        };
+        let features = leaf(LEAF_FEATURES);
+        let extended_features = leaf(LEAF_EXTENDED_FEATURES);
+        let extended_processor = leaf(LEAF_EXTENDED_PROCESSOR);
+        let power_management = leaf(LEAF_POWER_MANAGEMENT);
+
+        let base_family = (features.eax >> 8) & 0xf;
+        let base_model = (features.eax >> 4) & 0xf;
+        let family = match base_family {
+            0xf => base_family + ((features.eax >> 20) & 0xff),
+            _ => base_family,
+        };
+        let model = match base_family {
+            0x6 | 0xf => ((features.eax >> 12) & 0xf0) | base_model,
+            _ => base_model,
+        };
+
+        // AMD only has the cache parameter leaf with topology extensions
+        let cache_leaf = match vendor {
+            Vendor::Amd if extended_processor.ecx & (1 << 22) != 0 => {
+                Some(LEAF_AMD_CACHE_PARAMETERS).filter(|&leaf| leaf <= max_extended_leaf)
+            }
+            Vendor::Amd => None,
+            _ => Some(LEAF_CACHE_PARAMETERS).filter(|&leaf| leaf <= max_leaf),
+        };
+
+        Self {
+            vendor,
+            max_leaf,
+            max_extended_leaf,
+            family: family as u16,
+            model: model as u8,
+            stepping: (features.eax & 0xf) as u8,
+            initial_apic_id: (features.ebx >> 24) as u8,
+            clflush_line_size: ((features.ebx >> 8) & 0xff) as u16 * 8,
+
+            sse: bit(features.edx, 25),
+            sse2: bit(features.edx, 26),
+            sse3: bit(features.ecx, 0),
+            ssse3: bit(features.ecx, 9),
+            sse4_1: bit(features.ecx, 19),
+            sse4_2: bit(features.ecx, 20),
+            avx: bit(features.ecx, 28),
+            fxsr: bit(features.edx, 24),
+            xsave: bit(features.ecx, 26),
+            osxsave: bit(features.ecx, 27),
+            apic: bit(features.edx, 9),
+            x2apic: bit(features.ecx, 21),
+            tsc: bit(features.edx, 4),
+            tsc_deadline: bit(features.ecx, 24),
+            msr: bit(features.edx, 5),
+            pae: bit(features.edx, 6),
+            pge: bit(features.edx, 13),
+            pat: bit(features.edx, 16),
+            pcid: bit(features.ecx, 17),
+            rdrand: bit(features.ecx, 30),
+            hypervisor: bit(features.ecx, 31),
+
+            fsgsbase: bit(extended_features.ebx, 0),
+            avx2: bit(extended_features.ebx, 5),
+            smep: bit(extended_features.ebx, 7),
+            invpcid: bit(extended_features.ebx, 10),
+            rdseed: bit(extended_features.ebx, 18),
+            smap: bit(extended_features.ebx, 20),
+            umip: bit(extended_features.ecx, 2),
+            la57: bit(extended_features.ecx, 16),
+
+            syscall: bit(extended_processor.edx, 11),
+            nx: bit(extended_processor.edx, 20),
+            pages_1gb: bit(extended_processor.edx, 26),
+            rdtscp: bit(extended_processor.edx, 27),
+            long_mode: bit(extended_processor.edx, 29),
+            invariant_tsc: bit(power_management.edx, 8),
+
+            caches: cache_leaf.map_or([None; MAX_CACHES], |leaf| read_caches(&cpuid, leaf)),
+            topology: (max_leaf >= LEAF_TOPOLOGY)
+                .then(|| read_topology(&cpuid))
+                .flatten(),
+        }
+    }
+
+    /// The caches of the current cpu, `None` if the cpu doesn't describe them.
+    pub fn caches(&self) -> impl Iterator<Item = &Cache> {
+        self.caches.iter().map_while(Option::as_ref)
+    }
+
+    /// The thread, core and package of the current cpu, `None` if the cpu
+    /// doesn't have the extended topology leaf.
+    pub fn topology(&self) -> Option<Topology> {
+        self.topology
+    }
+}
+
+fn bit(value: u32, bit: u32) -> bool {
+    value & (1 << bit) != 0
+}
+
+fn read_caches(cpuid: &impl Fn(u32, u32) -> CpuidResult, leaf: u32) -> [Option<Cache>; MAX_CACHES] {
+    let mut caches = [None; MAX_CACHES];
+    for (subleaf, cache) in caches.iter_mut().enumerate() {
+        let result = cpuid(leaf, subleaf as u32);
+        let kind = match result.eax & 0x1f {
+            1 => CacheKind::Data,
+            2 => CacheKind::Instruction,
+            3 => CacheKind::Unified,
+            // No more caches
+            _ => break,
+        };
+        *cache = Some(Cache {
+            level: ((result.eax >> 5) & 0x7) as u8,
+            kind,
+            line_size: (result.ebx & 0xfff) + 1,
+            partitions: ((result.ebx >> 12) & 0x3ff) + 1,
+            ways: (result.ebx >> 22) + 1,
+            sets: result.ecx + 1,
+            shared_by: ((result.eax >> 14) & 0xfff) + 1,
+        });
+    }
+    caches
+}
+
+fn read_topology(cpuid: &impl Fn(u32, u32) -> CpuidResult) -> Option<Topology> {
+    const LEVEL_SMT: u32 = 1;
+
+    let mut topology = Topology {
+        x2apic_id: cpuid(LEAF_TOPOLOGY, 0).edx,
+        smt_shift: 0,
+        package_shift: 0,
+    };
+    // Each sub-leaf describes one level, the shift of the last one gives the
+    // package, an invalid level type ends the list
+    for subleaf in 0.. {
+        let result = cpuid(LEAF_TOPOLOGY, subleaf);
+        let shift = (result.eax & 0x1f) as u8;
+        match (result.ecx >> 8) & 0xff {
+            0 if subleaf == 0 => return None,
+            0 => break,
+            LEVEL_SMT => topology.smt_shift = shift,
+            _ => {}
+        }
+        topology.package_shift = shift;
+    }
+    Some(topology)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZERO: CpuidResult = regs(0, 0, 0, 0);
+
+    const fn regs(eax: u32, ebx: u32, ecx: u32, edx: u32) -> CpuidResult {
+        CpuidResult { eax, ebx, ecx, edx }
+    }
+
+    fn vendor_leaf(max_leaf: u32, vendor: &[u8; 12]) -> CpuidResult {
+        let word = |i: usize| u32::from_le_bytes(vendor[i..i + 4].try_into().unwrap());
+        regs(max_leaf, word(0), word(8), word(4))
+    }
+
+    // Answers `cpuid` from a list of (leaf, sub-leaf, result), anything else
+    // reads as zero
+    fn fake_cpuid(leaves: &[(u32, u32, CpuidResult)]) -> impl Fn(u32, u32) -> CpuidResult + '_ {
+        move |leaf, subleaf| {
+            leaves
+                .iter()
+                .find(|(l, s, _)| *l == leaf && *s == subleaf)
+                .map_or(ZERO, |(_, _, result)| *result)
+        }
+    }
+
+    #[test]
+    fn intel_family_model() {
+        let features = CpuFeatures::from_cpuid(fake_cpuid(&[
+            (LEAF_VENDOR, 0, vendor_leaf(0x16, b"GenuineIntel")),
+            (LEAF_FEATURES, 0, regs(0x0009_06ea, 0x0108_0800, 0, 0)),
+        ]));
+        assert_eq!(features.vendor, Vendor::Intel);
+        assert_eq!(features.family, 0x6);
+        assert_eq!(features.model, 0x9e);
+        assert_eq!(features.stepping, 0xa);
+        assert_eq!(features.initial_apic_id, 1);
+        assert_eq!(features.clflush_line_size, 64);
+    }
+
+    #[test]
+    fn amd_family_model() {
+        let features = CpuFeatures::from_cpuid(fake_cpuid(&[
+            (LEAF_VENDOR, 0, vendor_leaf(0x10, b"AuthenticAMD")),
+            (LEAF_FEATURES, 0, regs(0x0080_0f12, 0, 0, 0)),
+        ]));
+        assert_eq!(features.vendor, Vendor::Amd);
+        assert_eq!(features.family, 0x17);
+        assert_eq!(features.model, 0x01);
+        assert_eq!(features.stepping, 0x2);
+    }
+
+    #[test]
+    fn other_vendor_keeps_family() {
+        let features = CpuFeatures::from_cpuid(fake_cpuid(&[
+            (LEAF_VENDOR, 0, vendor_leaf(0x1, b"HygonGenuine")),
+            (LEAF_FEATURES, 0, regs(0x0000_0532, 0, 0, 0)),
+        ]));
+        assert_eq!(features.vendor, Vendor::Other(*b"HygonGenuine"));
+        // The extended model only applies to families 6 and 15
+        assert_eq!(features.family, 0x5);
+        assert_eq!(features.model, 0x3);
+    }
+
+    #[test]
+    fn leaves_above_max_are_ignored() {
+        let features = CpuFeatures::from_cpuid(fake_cpuid(&[
+            (LEAF_VENDOR, 0, vendor_leaf(0x1, b"GenuineIntel")),
+            (LEAF_EXTENDED_FEATURES, 0, regs(0, u32::MAX, u32::MAX, 0)),
+        ]));
+        assert!(!features.smep);
+        assert!(!features.la57);
+        assert!(features.topology().is_none());
+    }
+
+    #[test]
+    fn cache_sizes() {
+        // A 32 KiB 8-way L1 data cache with 64 byte lines, and a 1 MiB 16-way
+        // L2 shared by two threads
+        let features = CpuFeatures::from_cpuid(fake_cpuid(&[
+            (LEAF_VENDOR, 0, vendor_leaf(0x4, b"GenuineIntel")),
+            (
+                LEAF_CACHE_PARAMETERS,
+                0,
+                regs(1 | 1 << 5, 63 | 7 << 22, 63, 0),
+            ),
+            (
+                LEAF_CACHE_PARAMETERS,
+                1,
+                regs(3 | 2 << 5 | 1 << 14, 63 | 15 << 22, 1023, 0),
+            ),
+        ]));
+        let mut caches = features.caches();
+        let (l1, l2) = (caches.next().unwrap(), caches.next().unwrap());
+        assert!(caches.next().is_none());
+        assert_eq!(l1.level, 1);
+        assert_eq!(l1.kind, CacheKind::Data);
+        assert_eq!(l1.ways, 8);
+        assert_eq!(l1.size(), 32 * 1024);
+        assert_eq!(l2.level, 2);
+        assert_eq!(l2.kind, CacheKind::Unified);
+        assert_eq!(l2.shared_by, 2);
+        assert_eq!(l2.size(), 1024 * 1024);
+    }
+
+    #[test]
+    fn topology_split() {
+        // Two threads per core, eight cores per package
+        let features = CpuFeatures::from_cpuid(fake_cpuid(&[
+            (LEAF_VENDOR, 0, vendor_leaf(0xb, b"GenuineIntel")),
+            (LEAF_TOPOLOGY, 0, regs(1, 2, 1 << 8, 0b1_011_1)),
+            (LEAF_TOPOLOGY, 1, regs(4, 16, 2 << 8 | 1, 0b1_011_1)),
+        ]));
+        let topology = features.topology().unwrap();
+        assert_eq!(topology.smt_shift, 1);
+        assert_eq!(topology.package_shift, 4);
+        assert_eq!(topology.thread_id(), 1);
+        assert_eq!(topology.core_id(), 0b011);
+        assert_eq!(topology.package_id(), 1);
+    }
+
+    #[test]
+    fn topology_full_width_shift() {
+        let topology = Topology {
+            x2apic_id: 0xdead_beef,
+            smt_shift: 0,
+            package_shift: 32,
+        };
+        assert_eq!(topology.thread_id(), 0);
+        assert_eq!(topology.core_id(), 0xdead_beef);
+        assert_eq!(topology.package_id(), 0);
+    }
+}
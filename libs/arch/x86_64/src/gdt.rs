@@ -1,5 +1,7 @@
 use core::fmt::Debug;
 
+use crate::tss::TaskStateSegment;
+
 /// Base and limit are ignored in 64-bit mode, the whole address space is
 /// affected regardless.
 #[derive(Clone, Copy)]
@@ -50,6 +52,244 @@ impl GdtDesc {
     }
 }
 
+/// Requested or current privilege level, ring 0 is the kernel and ring 3 user
+/// mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PrivilegeLevel {
+    Ring0 = 0,
+    Ring1 = 1,
+    Ring2 = 2,
+    Ring3 = 3,
+}
+
+/// Refers to a descriptor in the GDT, the index is in bits 3-15 and the
+/// requested privilege level in bits 0-1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct SegmentSelector(pub u16);
+
+impl SegmentSelector {
+    pub const fn new(index: u16, rpl: PrivilegeLevel) -> Self {
+        Self((index << 3) | rpl as u16)
+    }
+
+    pub fn index(&self) -> u16 {
+        self.0 >> 3
+    }
+
+    pub fn rpl(&self) -> PrivilegeLevel {
+        match self.0 & 0b11 {
+            0 => PrivilegeLevel::Ring0,
+            1 => PrivilegeLevel::Ring1,
+            2 => PrivilegeLevel::Ring2,
+            _ => PrivilegeLevel::Ring3,
+        }
+    }
+}
+
+/// An entry to add to a `GlobalDescriptorTable`.
+#[derive(Clone, Copy, Debug)]
+pub enum Descriptor {
+    /// Code and data segments, base and limit are ignored in 64-bit mode
+    UserSegment(u64),
+    /// System segments like the TSS take up two entries
+    SystemSegment(SystemSegmentDesc),
+}
+
+impl Descriptor {
+    // access byte bits
+    const ACCESSED: u64 = 1 << 40;
+    const WRITABLE: u64 = 1 << 41;
+    const EXECUTABLE: u64 = 1 << 43;
+    const CODE_DATA: u64 = 1 << 44;
+    const DPL_3: u64 = 3 << 45;
+    const PRESENT: u64 = 1 << 47;
+    // flags
+    const LONG_MODE: u64 = 1 << 53;
+
+    // The accessed bit is set up front, otherwise the cpu writes it on first use
+    const COMMON: u64 = Self::PRESENT | Self::CODE_DATA | Self::ACCESSED | Self::WRITABLE;
+
+    /// 64-bit code segment for ring 0, access byte 0x9b.
+    pub const fn kernel_code_segment() -> Self {
+        Self::UserSegment(Self::COMMON | Self::EXECUTABLE | Self::LONG_MODE)
+    }
+
+    /// Data segment for ring 0, access byte 0x93.
+    pub const fn kernel_data_segment() -> Self {
+        Self::UserSegment(Self::COMMON)
+    }
+
+    /// 64-bit code segment for ring 3, access byte 0xfb.
+    pub const fn user_code_segment() -> Self {
+        Self::UserSegment(Self::COMMON | Self::EXECUTABLE | Self::LONG_MODE | Self::DPL_3)
+    }
+
+    /// Data segment for ring 3, access byte 0xf3.
+    pub const fn user_data_segment() -> Self {
+        Self::UserSegment(Self::COMMON | Self::DPL_3)
+    }
+
+    pub fn tss_segment(tss: &'static TaskStateSegment) -> Self {
+        Self::SystemSegment(SystemSegmentDesc::tss(tss))
+    }
+
+    fn privilege_level(&self) -> PrivilegeLevel {
+        match self {
+            Self::UserSegment(entry) if entry & Self::DPL_3 == Self::DPL_3 => PrivilegeLevel::Ring3,
+            _ => PrivilegeLevel::Ring0,
+        }
+    }
+}
+
+/// A GDT with room for `N` entries, the first of which is the null
+/// descriptor.
+#[derive(Clone, Debug)]
+#[repr(C, align(8))]
+pub struct GlobalDescriptorTable<const N: usize = 8> {
+    table: [u64; N],
+    len: usize,
+}
+
+impl<const N: usize> GlobalDescriptorTable<N> {
+    pub const fn new() -> Self {
+        Self {
+            table: [0; N],
+            len: 1,
+        }
+    }
+
+    /// Adds a descriptor and returns a selector for it, with the privilege
+    /// level of the descriptor as its requested privilege level.
+    ///
+    /// Panics if the table is full.
+    pub fn append(&mut self, descriptor: Descriptor) -> SegmentSelector {
+        let index = self.len;
+        match descriptor {
+            Descriptor::UserSegment(entry) => self.push(entry),
+            Descriptor::SystemSegment(desc) => {
+                let [low, high] = desc.to_entries();
+                self.push(low);
+                self.push(high);
+            }
+        }
+
+        SegmentSelector::new(index as u16, descriptor.privilege_level())
+    }
+
+    fn push(&mut self, entry: u64) {
+        assert!(self.len < N, "GDT is full");
+        self.table[self.len] = entry;
+        self.len += 1;
+    }
+
+    /// Decodes the entries added so far, the second half of system segments
+    /// included.
+    pub fn descriptors(&self) -> impl Iterator<Item = GdtDesc> + '_ {
+        self.table[..self.len]
+            .iter()
+            .map(|&entry| unsafe { core::mem::transmute::<u64, GdtDesc>(entry) })
+    }
+
+    /// Loads the table into GDTR. Segment registers keep their cached
+    /// descriptors until they are reloaded, see `set_cs` and `load_ss`.
+    pub fn load(&'static self) {
+        let gdtr = Gdtr {
+            limit: (self.len * core::mem::size_of::<u64>() - 1) as u16,
+            base: self.table.as_ptr() as u64,
+        };
+        unsafe {
+            core::arch::asm!("lgdt [{}]", in(reg) &gdtr, options(readonly, nostack, preserves_flags));
+        }
+    }
+}
+
+impl<const N: usize> Default for GlobalDescriptorTable<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Reloads CS through a far return, CS can't be written with `mov`.
+pub fn set_cs(selector: SegmentSelector) {
+    unsafe {
+        core::arch::asm!(
+            "push {selector}",
+            "lea {tmp}, [rip + 2f]",
+            "push {tmp}",
+            "retfq",
+            "2:",
+            selector = in(reg) selector.0 as u64,
+            tmp = lateout(reg) _,
+            options(preserves_flags),
+        );
+    }
+}
+
+pub fn load_ss(selector: SegmentSelector) {
+    unsafe {
+        core::arch::asm!("mov ss, {0:x}", in(reg) selector.0, options(nostack, preserves_flags));
+    }
+}
+
+pub fn load_ds(selector: SegmentSelector) {
+    unsafe {
+        core::arch::asm!("mov ds, {0:x}", in(reg) selector.0, options(nostack, preserves_flags));
+    }
+}
+
+pub fn load_es(selector: SegmentSelector) {
+    unsafe {
+        core::arch::asm!("mov es, {0:x}", in(reg) selector.0, options(nostack, preserves_flags));
+    }
+}
+
+/// System segments take up two GDT entries in 64-bit mode, the second one
+/// holds the upper half of the base.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct SystemSegmentDesc {
+    low: GdtDesc,
+    base_upper: u32,
+    _reserved: u32,
+}
+
+impl SystemSegmentDesc {
+    // access byte(0x89) = [present, type = available 64-bit TSS]
+    const TSS_ACCESS: u8 = 0x89;
+
+    pub fn tss(tss: &'static TaskStateSegment) -> Self {
+        let base = tss as *const TaskStateSegment as u64;
+        let limit = (core::mem::size_of::<TaskStateSegment>() - 1) as u32;
+        Self {
+            low: GdtDesc {
+                limit_low: limit as u16,
+                base_low: base as u16,
+                base_mid: (base >> 16) as u8,
+                access: GdtDescAccess(Self::TSS_ACCESS),
+                flags_limit: GdtDescFlags((limit >> 16) as u8 & 0xF),
+                base_high: (base >> 24) as u8,
+            },
+            base_upper: (base >> 32) as u32,
+            _reserved: 0,
+        }
+    }
+
+    pub fn base(&self) -> u64 {
+        ((self.base_upper as u64) << 32) | self.low.base() as u64
+    }
+
+    pub fn limit(&self) -> u32 {
+        self.low.limit()
+    }
+
+    /// The two raw GDT entries, in order.
+    pub fn to_entries(self) -> [u64; 2] {
+        unsafe { core::mem::transmute(self) }
+    }
+}
+
 #[derive(Debug)]
 pub struct GdtTableIter {
     base: *const GdtDesc,
@@ -75,6 +315,13 @@ impl Iterator for GdtTableIter {
 #[repr(C)]
 pub struct GdtDescAccess(u8);
 
+impl GdtDescAccess {
+    /// The descriptor privilege level, bits 5 and 6.
+    pub fn dpl(&self) -> u8 {
+        (self.0 >> 5) & 0b11
+    }
+}
+
 impl Debug for GdtDescAccess {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         f.debug_struct("GdtDescAccess")
@@ -83,7 +330,7 @@ impl Debug for GdtDescAccess {
             .field("dc", &(self.0 & (1 << 2) != 0))
             .field("e", &(self.0 & (1 << 3) != 0))
             .field("s", &(self.0 & (1 << 4) != 0))
-            .field("dpl", &((self.0 << 5) & 0xF))
+            .field("dpl", &self.dpl())
             .field("p", &(self.0 & (1 << 7) != 0))
             .finish()
     }
@@ -95,7 +342,7 @@ pub struct GdtDescFlags(u8);
 
 impl Debug for GdtDescFlags {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        f.debug_struct("GdtDescAccess")
+        f.debug_struct("GdtDescFlags")
             .field("l", &(self.0 & (1 << 5) != 0))
             .field("db", &(self.0 & (1 << 6) != 0))
             .field("g", &(self.0 & (1 << 7) != 0))
@@ -119,3 +366,17 @@ impl Gdtr {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_dpl() {
+        assert_eq!(GdtDescAccess(0x9a).dpl(), 0);
+        assert_eq!(GdtDescAccess(0x92).dpl(), 0);
+        assert_eq!(GdtDescAccess(0xfb).dpl(), 3);
+        assert_eq!(GdtDescAccess(0xf3).dpl(), 3);
+        assert_eq!(GdtDescAccess(0xb2).dpl(), 1);
+    }
+}
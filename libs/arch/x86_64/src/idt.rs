@@ -1,25 +1,206 @@
-#[derive(Debug)]
+use crate::gdt::Gdtr;
+use crate::gdt::PrivilegeLevel;
+
+/// The IDT, with the 32 exception vectors followed by the 224 vectors free for
+/// interrupts. Handlers are installed by address, see `Entry::set_handler_addr`.
+#[derive(Clone, Debug)]
+#[repr(C, align(16))]
+pub struct InterruptDescriptorTable {
+    pub divide_error: Entry,
+    pub debug: Entry,
+    pub non_maskable_interrupt: Entry,
+    pub breakpoint: Entry,
+    pub overflow: Entry,
+    pub bound_range_exceeded: Entry,
+    pub invalid_opcode: Entry,
+    pub device_not_available: Entry,
+    pub double_fault: Entry,
+    coprocessor_segment_overrun: Entry,
+    pub invalid_tss: Entry,
+    pub segment_not_present: Entry,
+    pub stack_segment_fault: Entry,
+    pub general_protection_fault: Entry,
+    pub page_fault: Entry,
+    reserved_1: Entry,
+    pub x87_floating_point: Entry,
+    pub alignment_check: Entry,
+    pub machine_check: Entry,
+    pub simd_floating_point: Entry,
+    pub virtualization: Entry,
+    pub control_protection: Entry,
+    reserved_2: [Entry; 6],
+    pub hypervisor_injection: Entry,
+    pub vmm_communication: Entry,
+    pub security: Entry,
+    reserved_3: Entry,
+    interrupts: [Entry; 256 - 32],
+}
+
+impl InterruptDescriptorTable {
+    /// A table where every entry is missing.
+    pub const fn new() -> Self {
+        Self {
+            divide_error: Entry::missing(),
+            debug: Entry::missing(),
+            non_maskable_interrupt: Entry::missing(),
+            breakpoint: Entry::missing(),
+            overflow: Entry::missing(),
+            bound_range_exceeded: Entry::missing(),
+            invalid_opcode: Entry::missing(),
+            device_not_available: Entry::missing(),
+            double_fault: Entry::missing(),
+            coprocessor_segment_overrun: Entry::missing(),
+            invalid_tss: Entry::missing(),
+            segment_not_present: Entry::missing(),
+            stack_segment_fault: Entry::missing(),
+            general_protection_fault: Entry::missing(),
+            page_fault: Entry::missing(),
+            reserved_1: Entry::missing(),
+            x87_floating_point: Entry::missing(),
+            alignment_check: Entry::missing(),
+            machine_check: Entry::missing(),
+            simd_floating_point: Entry::missing(),
+            virtualization: Entry::missing(),
+            control_protection: Entry::missing(),
+            reserved_2: [Entry::missing(); 6],
+            hypervisor_injection: Entry::missing(),
+            vmm_communication: Entry::missing(),
+            security: Entry::missing(),
+            reserved_3: Entry::missing(),
+            interrupts: [Entry::missing(); 256 - 32],
+        }
+    }
+
+    /// Entry of any vector, exceptions included.
+    pub fn entry_mut(&mut self, vector: u8) -> &mut Entry {
+        // The table is just 256 entries in a row
+        unsafe { &mut *(self as *mut Self as *mut Entry).add(vector as usize) }
+    }
+
+    /// Loads the table into IDTR on the current cpu.
+    pub fn load(&'static self) {
+        // IDTR has the same layout as GDTR
+        let idtr = Gdtr {
+            limit: (core::mem::size_of::<Self>() - 1) as u16,
+            base: self as *const Self as u64,
+        };
+        unsafe {
+            core::arch::asm!("lidt [{}]", in(reg) &idtr, options(readonly, nostack, preserves_flags));
+        }
+    }
+}
+
+const _: () = assert!(core::mem::size_of::<InterruptDescriptorTable>() == 256 * 16);
+
+impl Default for InterruptDescriptorTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A gate descriptor.
+#[derive(Clone, Copy, Debug)]
 #[repr(C)]
-pub struct IdtEntry {
-    fn_ptr_low: u16,  // [0:15]	The lower bits of the pointer to the handler function.
-    gdt: u16,         // selector	Selector of a code segment in the global descriptor table.
-    options: u16,     //	(see below)
-    fn_ptr_mid: u16,  // [16:31]	The middle bits of the pointer to the handler function.
+pub struct Entry {
+    fn_ptr_low: u16, // [0:15]	The lower bits of the pointer to the handler function.
+    gdt: u16,        // selector	Selector of a code segment in the global descriptor table.
+    options: EntryOptions,
+    fn_ptr_mid: u16, // [16:31]	The middle bits of the pointer to the handler function.
     fn_ptr_high: u32, // [32:63]	The remaining bits of the pointer to the handler function.
-    _reserved: u32,   //
+    _reserved: u32,  //
 }
 
-impl IdtEntry {
-    pub fn new(fn_ptr: u64, gdt: u16, options: u16) -> Self {
+impl Entry {
+    /// An entry that isn't present, using it raises a #NP.
+    pub const fn missing() -> Self {
         Self {
-            fn_ptr_low: fn_ptr as u16,
-            gdt,
-            options,
-            fn_ptr_mid: (fn_ptr >> 16) as u16,
-            fn_ptr_high: (fn_ptr >> 32) as u32,
+            fn_ptr_low: 0,
+            gdt: 0,
+            options: EntryOptions::minimal(),
+            fn_ptr_mid: 0,
+            fn_ptr_high: 0,
             _reserved: 0,
         }
     }
+
+    /// Points the entry at `addr` in the current code segment and marks it
+    /// present, returning the options for further changes.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be an interrupt handler that expects the stack the cpu
+    /// sets up for this vector.
+    pub unsafe fn set_handler_addr(&mut self, addr: u64) -> &mut EntryOptions {
+        self.fn_ptr_low = addr as u16;
+        self.fn_ptr_mid = (addr >> 16) as u16;
+        self.fn_ptr_high = (addr >> 32) as u32;
+        self.gdt = read_cs();
+        self.options.set_present(true);
+        &mut self.options
+    }
+
+    pub fn handler_addr(&self) -> u64 {
+        self.fn_ptr_low as u64 | (self.fn_ptr_mid as u64) << 16 | (self.fn_ptr_high as u64) << 32
+    }
+
+    pub fn options_mut(&mut self) -> &mut EntryOptions {
+        &mut self.options
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GateType {
+    /// Clears IF on entry, so that the handler isn't interrupted
+    Interrupt,
+    /// Leaves IF as it is
+    Trap,
+}
+
+/// The options word of a gate descriptor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct EntryOptions(u16);
+
+impl EntryOptions {
+    const PRESENT: u16 = 1 << 15;
+    const GATE_TYPE_SHIFT: u16 = 8;
+    const DPL_SHIFT: u16 = 13;
+
+    /// Not present, an interrupt gate with DPL 0 and no IST stack.
+    pub const fn minimal() -> Self {
+        Self(0xe << Self::GATE_TYPE_SHIFT)
+    }
+
+    pub fn set_present(&mut self, present: bool) -> &mut Self {
+        match present {
+            true => self.0 |= Self::PRESENT,
+            false => self.0 &= !Self::PRESENT,
+        }
+        self
+    }
+
+    pub fn set_gate_type(&mut self, gate_type: GateType) -> &mut Self {
+        let gate_type = match gate_type {
+            GateType::Interrupt => 0xe,
+            GateType::Trap => 0xf,
+        };
+        self.0 = (self.0 & !(0xf << Self::GATE_TYPE_SHIFT)) | gate_type << Self::GATE_TYPE_SHIFT;
+        self
+    }
+
+    /// The lowest privilege level allowed to raise the vector with `int`.
+    pub fn set_privilege_level(&mut self, dpl: PrivilegeLevel) -> &mut Self {
+        self.0 = (self.0 & !(0b11 << Self::DPL_SHIFT)) | (dpl as u16) << Self::DPL_SHIFT;
+        self
+    }
+
+    /// Switches to stack `index` of the interrupt stack table before calling
+    /// the handler, 0 keeps the current stack.
+    pub fn set_ist_index(&mut self, index: u8) -> &mut Self {
+        assert!(index < 8);
+        self.0 = (self.0 & !0b111) | index as u16;
+        self
+    }
 }
 
 pub fn read_cs() -> u16 {
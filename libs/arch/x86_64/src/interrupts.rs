@@ -0,0 +1,32 @@
+/// Enables maskable interrupts on the current cpu.
+pub fn enable() {
+    unsafe { core::arch::asm!("sti", options(nostack)) };
+}
+
+/// Disables maskable interrupts on the current cpu.
+pub fn disable() {
+    unsafe { core::arch::asm!("cli", options(nostack)) };
+}
+
+/// Whether maskable interrupts are enabled on the current cpu, i.e. RFLAGS.IF.
+pub fn are_enabled() -> bool {
+    let rflags: u64;
+    unsafe { core::arch::asm!("pushfq; pop {}", out(reg) rflags, options(nomem, preserves_flags)) };
+    rflags & (1 << 9) != 0
+}
+
+/// Runs `f` with interrupts disabled, restoring the previous interrupt state
+/// afterwards so that calls can be nested.
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let enabled = are_enabled();
+    if enabled {
+        disable();
+    }
+
+    let result = f();
+    if enabled {
+        enable();
+    }
+
+    result
+}
@@ -4,8 +4,14 @@
 use common::addr::PhysAddr;
 
 pub mod control;
-pub mod efer;
+pub mod cpuid;
 pub mod flags;
 pub mod gdt;
 pub mod idt;
+pub mod interrupts;
+pub mod msr;
 pub mod paging;
+pub mod port;
+pub mod tlb;
+pub mod tsc;
+pub mod tss;
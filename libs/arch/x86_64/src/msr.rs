@@ -0,0 +1,399 @@
+use common::addr::PhysAddr;
+
+use crate::gdt::SegmentSelector;
+
+pub const IA32_APIC_BASE: u32 = 0x0000_001b;
+pub const IA32_MISC_ENABLE: u32 = 0x0000_01a0;
+pub const IA32_PAT: u32 = 0x0000_0277;
+pub const IA32_TSC_DEADLINE: u32 = 0x0000_06e0;
+pub const IA32_EFER: u32 = 0xc000_0080;
+pub const IA32_STAR: u32 = 0xc000_0081;
+pub const IA32_LSTAR: u32 = 0xc000_0082;
+pub const IA32_FMASK: u32 = 0xc000_0084;
+pub const IA32_FS_BASE: u32 = 0xc000_0100;
+pub const IA32_GS_BASE: u32 = 0xc000_0101;
+pub const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+/// # Safety
+///
+/// `msr` must exist on the current cpu, otherwise a #GP is raised.
+pub unsafe fn rdmsr(msr: u32) -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        core::arch::asm!("rdmsr", out("eax") low, out("edx") high, in("ecx") msr, options(nomem, nostack, preserves_flags));
+    }
+    ((high as u64) << 32) | (low as u64)
+}
+
+/// # Safety
+///
+/// `msr` must exist on the current cpu and accept `value`, and changing it
+/// must not break any assumptions made by the rest of the kernel.
+pub unsafe fn wrmsr(msr: u32, value: u64) {
+    let low = value as u32;
+    let high = (value >> 32) as u32;
+    unsafe {
+        core::arch::asm!("wrmsr", in("eax") low, in("edx") high, in("ecx") msr, options(nostack, preserves_flags));
+    }
+}
+
+fn set_bit(value: &mut u64, bit: u32, set: bool) {
+    match set {
+        true => *value |= 1 << bit,
+        false => *value &= !(1 << bit),
+    }
+}
+
+/// IA32_APIC_BASE, where the local APIC is mapped and how it is enabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ApicBase(u64);
+
+impl ApicBase {
+    const BSP: u32 = 8;
+    const X2APIC_ENABLE: u32 = 10;
+    const GLOBAL_ENABLE: u32 = 11;
+    const BASE_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+    pub fn read() -> Self {
+        Self(unsafe { rdmsr(IA32_APIC_BASE) })
+    }
+
+    /// # Safety
+    ///
+    /// Moving or disabling the local APIC affects everything using it.
+    pub unsafe fn write(self) {
+        unsafe { wrmsr(IA32_APIC_BASE, self.0) }
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Set on the bootstrap processor, read only.
+    pub fn bsp(self) -> bool {
+        self.0 & (1 << Self::BSP) != 0
+    }
+
+    pub fn x2apic_enable(self) -> bool {
+        self.0 & (1 << Self::X2APIC_ENABLE) != 0
+    }
+
+    pub fn set_x2apic_enable(&mut self, enable: bool) -> &mut Self {
+        set_bit(&mut self.0, Self::X2APIC_ENABLE, enable);
+        self
+    }
+
+    pub fn global_enable(self) -> bool {
+        self.0 & (1 << Self::GLOBAL_ENABLE) != 0
+    }
+
+    pub fn set_global_enable(&mut self, enable: bool) -> &mut Self {
+        set_bit(&mut self.0, Self::GLOBAL_ENABLE, enable);
+        self
+    }
+
+    pub fn base_addr(self) -> PhysAddr {
+        PhysAddr::new(self.0 & Self::BASE_MASK)
+    }
+
+    pub fn set_base_addr(&mut self, addr: PhysAddr) -> &mut Self {
+        assert!(addr.as_u64() & !Self::BASE_MASK == 0, "unaligned apic base");
+        self.0 = (self.0 & !Self::BASE_MASK) | addr.as_u64();
+        self
+    }
+}
+
+/// IA32_EFER, the extended feature enables.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Efer(u64);
+
+impl Efer {
+    const SCE: u32 = 0;
+    const LME: u32 = 8;
+    const LMA: u32 = 10;
+    const NXE: u32 = 11;
+
+    pub fn read() -> Self {
+        Self(unsafe { rdmsr(IA32_EFER) })
+    }
+
+    /// # Safety
+    ///
+    /// Clearing LME or NXE while in use breaks paging.
+    pub unsafe fn write(self) {
+        unsafe { wrmsr(IA32_EFER, self.0) }
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// System call extensions, enables `syscall` and `sysret`.
+    pub fn sce(self) -> bool {
+        self.0 & (1 << Self::SCE) != 0
+    }
+
+    pub fn set_sce(&mut self, enable: bool) -> &mut Self {
+        set_bit(&mut self.0, Self::SCE, enable);
+        self
+    }
+
+    /// Long mode enable.
+    pub fn lme(self) -> bool {
+        self.0 & (1 << Self::LME) != 0
+    }
+
+    pub fn set_lme(&mut self, enable: bool) -> &mut Self {
+        set_bit(&mut self.0, Self::LME, enable);
+        self
+    }
+
+    /// Long mode active, set by the cpu.
+    pub fn lma(self) -> bool {
+        self.0 & (1 << Self::LMA) != 0
+    }
+
+    /// No-execute enable, makes the XD bit of page table entries usable.
+    pub fn nxe(self) -> bool {
+        self.0 & (1 << Self::NXE) != 0
+    }
+
+    pub fn set_nxe(&mut self, enable: bool) -> &mut Self {
+        set_bit(&mut self.0, Self::NXE, enable);
+        self
+    }
+}
+
+/// IA32_STAR, the segments loaded by `syscall` and `sysret`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Star(u64);
+
+impl Star {
+    pub fn read() -> Self {
+        Self(unsafe { rdmsr(IA32_STAR) })
+    }
+
+    /// # Safety
+    ///
+    /// The selectors must match the layout of the loaded GDT.
+    pub unsafe fn write(self) {
+        unsafe { wrmsr(IA32_STAR, self.0) }
+    }
+
+    /// `syscall` loads CS from this selector and SS from the one after it.
+    pub fn syscall_base(self) -> SegmentSelector {
+        SegmentSelector((self.0 >> 32) as u16)
+    }
+
+    pub fn set_syscall_base(&mut self, selector: SegmentSelector) -> &mut Self {
+        self.0 = (self.0 & !(0xffff << 32)) | (selector.0 as u64) << 32;
+        self
+    }
+
+    /// `sysret` to 64-bit mode loads SS from the selector after this one and
+    /// CS from the one after that, so user data has to precede user code.
+    pub fn sysret_base(self) -> SegmentSelector {
+        SegmentSelector((self.0 >> 48) as u16)
+    }
+
+    pub fn set_sysret_base(&mut self, selector: SegmentSelector) -> &mut Self {
+        self.0 = (self.0 & !(0xffff << 48)) | (selector.0 as u64) << 48;
+        self
+    }
+}
+
+// MSRs holding a single address or mask
+macro_rules! value_msr {
+    ($($(#[$doc:meta])* $name:ident = $msr:ident,)*) => {
+        $(
+            $(#[$doc])*
+            #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+            pub struct $name;
+
+            impl $name {
+                pub fn read() -> u64 {
+                    unsafe { rdmsr($msr) }
+                }
+
+                /// # Safety
+                ///
+                /// Nothing may depend on the previous value.
+                pub unsafe fn write(value: u64) {
+                    unsafe { wrmsr($msr, value) }
+                }
+            }
+        )*
+    };
+}
+
+value_msr! {
+    /// IA32_LSTAR, the entry point of `syscall` in 64-bit mode.
+    LStar = IA32_LSTAR,
+    /// IA32_FMASK, the RFLAGS bits cleared by `syscall`.
+    SfMask = IA32_FMASK,
+    /// IA32_FS_BASE, the base of the FS segment.
+    FsBase = IA32_FS_BASE,
+    /// IA32_GS_BASE, the base of the GS segment.
+    GsBase = IA32_GS_BASE,
+    /// IA32_KERNEL_GS_BASE, swapped with the GS base by `swapgs`.
+    KernelGsBase = IA32_KERNEL_GS_BASE,
+}
+
+/// IA32_TSC_DEADLINE, raises the local APIC timer interrupt once the TSC
+/// reaches it when the timer is in TSC deadline mode, 0 disarms it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TscDeadline;
+
+impl TscDeadline {
+    /// # Safety
+    ///
+    /// The cpu must support the TSC deadline mode, see
+    /// `CpuFeatures::tsc_deadline`, or the read raises a #GP.
+    pub unsafe fn read() -> u64 {
+        unsafe { rdmsr(IA32_TSC_DEADLINE) }
+    }
+
+    /// # Safety
+    ///
+    /// The cpu must support the TSC deadline mode, see
+    /// `CpuFeatures::tsc_deadline`, or the write raises a #GP.
+    pub unsafe fn write(value: u64) {
+        unsafe { wrmsr(IA32_TSC_DEADLINE, value) }
+    }
+}
+
+/// The memory types that can be selected through the PAT.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PatMemoryType {
+    Uncacheable = 0,
+    WriteCombining = 1,
+    WriteThrough = 4,
+    WriteProtected = 5,
+    WriteBack = 6,
+    // Uncacheable, but can be overridden by MTRRs selecting write combining
+    UncachedMinus = 7,
+}
+
+impl PatMemoryType {
+    fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0 => Some(Self::Uncacheable),
+            1 => Some(Self::WriteCombining),
+            4 => Some(Self::WriteThrough),
+            5 => Some(Self::WriteProtected),
+            6 => Some(Self::WriteBack),
+            7 => Some(Self::UncachedMinus),
+            _ => None,
+        }
+    }
+}
+
+/// IA32_PAT, the memory types of the 8 entries selected by the PAT, PCD and
+/// PWT bits of page table entries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pat(u64);
+
+impl Pat {
+    pub const ENTRIES: usize = 8;
+
+    pub fn read() -> Self {
+        Self(unsafe { rdmsr(IA32_PAT) })
+    }
+
+    /// # Safety
+    ///
+    /// Changing the type of an entry used by existing mappings changes how
+    /// they are cached, the TLBs and caches have to be flushed afterwards.
+    pub unsafe fn write(self) {
+        unsafe { wrmsr(IA32_PAT, self.0) }
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// The memory type of `index`, `None` for reserved encodings.
+    pub fn entry(self, index: usize) -> Option<PatMemoryType> {
+        assert!(index < Self::ENTRIES);
+        PatMemoryType::from_bits((self.0 >> (index * 8)) as u8 & 0b111)
+    }
+
+    pub fn set_entry(&mut self, index: usize, memory_type: PatMemoryType) -> &mut Self {
+        assert!(index < Self::ENTRIES);
+        self.0 = (self.0 & !(0xff << (index * 8))) | (memory_type as u64) << (index * 8);
+        self
+    }
+}
+
+/// IA32_MISC_ENABLE, Intel only.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MiscEnable(u64);
+
+impl MiscEnable {
+    const FAST_STRINGS: u32 = 0;
+    const MONITOR: u32 = 18;
+    const LIMIT_CPUID_MAXVAL: u32 = 22;
+    const XD_DISABLE: u32 = 34;
+
+    /// # Safety
+    ///
+    /// Must only be used on Intel cpus, see `CpuFeatures::vendor`, the MSR
+    /// doesn't exist elsewhere and the read raises a #GP.
+    pub unsafe fn read() -> Self {
+        Self(unsafe { rdmsr(IA32_MISC_ENABLE) })
+    }
+
+    /// # Safety
+    ///
+    /// Must only be used on Intel cpus, and features may not be disabled
+    /// while in use.
+    pub unsafe fn write(self) {
+        unsafe { wrmsr(IA32_MISC_ENABLE, self.0) }
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Fast `rep movs` and `rep stos`.
+    pub fn fast_strings(self) -> bool {
+        self.0 & (1 << Self::FAST_STRINGS) != 0
+    }
+
+    pub fn set_fast_strings(&mut self, enable: bool) -> &mut Self {
+        set_bit(&mut self.0, Self::FAST_STRINGS, enable);
+        self
+    }
+
+    /// `monitor` and `mwait` are available.
+    pub fn monitor(self) -> bool {
+        self.0 & (1 << Self::MONITOR) != 0
+    }
+
+    pub fn set_monitor(&mut self, enable: bool) -> &mut Self {
+        set_bit(&mut self.0, Self::MONITOR, enable);
+        self
+    }
+
+    /// Limits the highest basic CPUID leaf to 2, for old operating systems.
+    pub fn limit_cpuid_maxval(self) -> bool {
+        self.0 & (1 << Self::LIMIT_CPUID_MAXVAL) != 0
+    }
+
+    pub fn set_limit_cpuid_maxval(&mut self, enable: bool) -> &mut Self {
+        set_bit(&mut self.0, Self::LIMIT_CPUID_MAXVAL, enable);
+        self
+    }
+
+    /// Hides the XD bit, EFER.NXE can't be set while this is set.
+    pub fn xd_disable(self) -> bool {
+        self.0 & (1 << Self::XD_DISABLE) != 0
+    }
+
+    pub fn set_xd_disable(&mut self, disable: bool) -> &mut Self {
+        set_bit(&mut self.0, Self::XD_DISABLE, disable);
+        self
+    }
+}
@@ -87,6 +87,34 @@ impl<'a, M: PageTableFrameMapper> MappedPageTable<'a, M> {
         Ok(())
     }
 
+    /// Maps memory mapped device registers, writable with caching disabled.
+    pub fn map_mmio<F: FrameAllocator>(
+        &mut self,
+        page: VirtAddr,
+        frame: PhysAddr,
+        frame_allocator: &F,
+    ) -> Result<(), ()> {
+        self.map(page, frame, frame_allocator, true)?;
+        let p1_entry = self.p1_entry_mut(page)?;
+        p1_entry.set_page_level_cache_disable(true);
+        p1_entry.set_page_level_write_through(true);
+        Ok(())
+    }
+
+    fn p1_entry_mut(&mut self, page: VirtAddr) -> Result<&mut PageTableEntry, ()> {
+        let p4 = &mut self.level_4_table;
+        let p3 = self
+            .table_walker
+            .get_next_table_mut(&mut p4[page.p4_index()])?;
+        let p2 = self
+            .table_walker
+            .get_next_table_mut(&mut p3[page.p3_index()])?;
+        let p1 = self
+            .table_walker
+            .get_next_table_mut(&mut p2[page.p2_index()])?;
+        Ok(&mut p1[page.p1_index()])
+    }
+
     pub fn map_1gb<F: FrameAllocator>(
         &mut self,
         page: VirtAddr,
@@ -241,9 +269,9 @@ impl<M: PageTableFrameMapper> PageTableWalker<M> {
         entry: &'a mut PageTableEntry,
         frame_allocator: F,
     ) -> Result<&'a mut PageTable, ()> {
-        let frame = if !entry.is_present() {
-            // TODO: make sure the frame is zerod
-            let frame = frame_allocator.allocate_frame().unwrap();
+        let created = !entry.is_present();
+        let frame = if created {
+            let frame = frame_allocator.allocate_frame().map_err(|_| ())?;
             *entry = PageTableEntry::empty();
             entry.set_present(true);
             entry.set_is_page(false);
@@ -255,12 +283,22 @@ impl<M: PageTableFrameMapper> PageTableWalker<M> {
         };
 
         // TODO: there is no validation that the addr is virt before creating page table ref
-        Ok(unsafe {
+        let table: &mut PageTable = unsafe {
             &mut *self
                 .page_table_frame_mapper
                 .frame_to_page(frame)
                 .as_ptr_mut()
-        })
+        };
+
+        // Freshly allocated frames contain whatever was there before
+        if created {
+            table
+                .entries
+                .iter_mut()
+                .for_each(|entry| *entry = PageTableEntry::empty());
+        }
+
+        Ok(table)
     }
 }
 
@@ -277,6 +315,10 @@ impl PageTableFrameOffsetMapper {
     pub const fn new(offset: u64) -> Self {
         Self { offset }
     }
+
+    pub fn page_to_frame(&self, page: VirtAddr) -> PhysAddr {
+        PhysAddr::new(page.as_u64() - self.offset)
+    }
 }
 
 impl PageTableFrameMapper for PageTableFrameOffsetMapper {
@@ -0,0 +1,167 @@
+use core::marker::PhantomData;
+
+/// Values that can be read from an I/O port, a byte, word or doubleword.
+pub trait PortRead {
+    /// # Safety
+    ///
+    /// Reading a port can have side effects on the device behind it.
+    unsafe fn read_from_port(port: u16) -> Self;
+}
+
+/// Values that can be written to an I/O port, a byte, word or doubleword.
+pub trait PortWrite {
+    /// # Safety
+    ///
+    /// Writing a port can have side effects on the device behind it.
+    unsafe fn write_to_port(port: u16, value: Self);
+}
+
+impl PortRead for u8 {
+    unsafe fn read_from_port(port: u16) -> Self {
+        let value: u8;
+        unsafe {
+            core::arch::asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+        }
+        value
+    }
+}
+
+impl PortRead for u16 {
+    unsafe fn read_from_port(port: u16) -> Self {
+        let value: u16;
+        unsafe {
+            core::arch::asm!("in ax, dx", out("ax") value, in("dx") port, options(nomem, nostack, preserves_flags));
+        }
+        value
+    }
+}
+
+impl PortRead for u32 {
+    unsafe fn read_from_port(port: u16) -> Self {
+        let value: u32;
+        unsafe {
+            core::arch::asm!("in eax, dx", out("eax") value, in("dx") port, options(nomem, nostack, preserves_flags));
+        }
+        value
+    }
+}
+
+impl PortWrite for u8 {
+    unsafe fn write_to_port(port: u16, value: Self) {
+        unsafe {
+            core::arch::asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+        }
+    }
+}
+
+impl PortWrite for u16 {
+    unsafe fn write_to_port(port: u16, value: Self) {
+        unsafe {
+            core::arch::asm!("out dx, ax", in("dx") port, in("ax") value, options(nomem, nostack, preserves_flags));
+        }
+    }
+}
+
+impl PortWrite for u32 {
+    unsafe fn write_to_port(port: u16, value: Self) {
+        unsafe {
+            core::arch::asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags));
+        }
+    }
+}
+
+/// An I/O port that is both read and written with values of type `T`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Port<T> {
+    port: u16,
+    _value: PhantomData<T>,
+}
+
+impl<T> Port<T> {
+    pub const fn new(port: u16) -> Self {
+        Self {
+            port,
+            _value: PhantomData,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl<T: PortRead> Port<T> {
+    /// # Safety
+    ///
+    /// Reading a port can have side effects on the device behind it.
+    pub unsafe fn read(&mut self) -> T {
+        unsafe { T::read_from_port(self.port) }
+    }
+}
+
+impl<T: PortWrite> Port<T> {
+    /// # Safety
+    ///
+    /// Writing a port can have side effects on the device behind it.
+    pub unsafe fn write(&mut self, value: T) {
+        unsafe { T::write_to_port(self.port, value) }
+    }
+}
+
+/// An I/O port that is only read, like a status register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortReadOnly<T> {
+    port: u16,
+    _value: PhantomData<T>,
+}
+
+impl<T> PortReadOnly<T> {
+    pub const fn new(port: u16) -> Self {
+        Self {
+            port,
+            _value: PhantomData,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl<T: PortRead> PortReadOnly<T> {
+    /// # Safety
+    ///
+    /// Reading a port can have side effects on the device behind it.
+    pub unsafe fn read(&mut self) -> T {
+        unsafe { T::read_from_port(self.port) }
+    }
+}
+
+/// An I/O port that is only written, like a command register.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortWriteOnly<T> {
+    port: u16,
+    _value: PhantomData<T>,
+}
+
+impl<T> PortWriteOnly<T> {
+    pub const fn new(port: u16) -> Self {
+        Self {
+            port,
+            _value: PhantomData,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl<T: PortWrite> PortWriteOnly<T> {
+    /// # Safety
+    ///
+    /// Writing a port can have side effects on the device behind it.
+    pub unsafe fn write(&mut self, value: T) {
+        unsafe { T::write_to_port(self.port, value) }
+    }
+}
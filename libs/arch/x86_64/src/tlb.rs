@@ -0,0 +1,20 @@
+use common::addr::VirtAddr;
+
+/// Invalidates the TLB entry for the page containing `addr` on the current cpu.
+#[inline(always)]
+pub fn flush(addr: VirtAddr) {
+    unsafe { core::arch::asm!("invlpg [{}]", in(reg) addr.as_u64(), options(nostack)) }
+}
+
+/// Invalidates all non-global TLB entries on the current cpu by reloading cr3.
+#[inline(always)]
+pub fn flush_all() {
+    unsafe {
+        core::arch::asm!(
+            "mov {0}, cr3",
+            "mov cr3, {0}",
+            out(reg) _,
+            options(nostack),
+        )
+    }
+}
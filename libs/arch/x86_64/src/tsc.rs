@@ -0,0 +1,4 @@
+/// Reads the time stamp counter of the current cpu.
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
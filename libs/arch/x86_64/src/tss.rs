@@ -0,0 +1,49 @@
+/// The 64-bit task state segment, which only holds the stacks the cpu
+/// switches to on privilege changes and through the interrupt stack table.
+#[derive(Clone, Copy, Debug)]
+#[repr(C, packed(4))]
+pub struct TaskStateSegment {
+    _reserved_1: u32,
+    /// Stacks loaded when an interrupt switches to ring 0, 1 or 2.
+    pub privilege_stack_table: [u64; 3],
+    _reserved_2: u64,
+    /// Stacks picked by the IST index of an IDT entry, IST 1 is at index 0.
+    pub interrupt_stack_table: [u64; 7],
+    _reserved_3: u64,
+    _reserved_4: u16,
+    /// Offset of the I/O permission bitmap from the start of the segment.
+    pub iomap_base: u16,
+}
+
+impl TaskStateSegment {
+    pub const fn new() -> Self {
+        Self {
+            _reserved_1: 0,
+            privilege_stack_table: [0; 3],
+            _reserved_2: 0,
+            interrupt_stack_table: [0; 7],
+            _reserved_3: 0,
+            _reserved_4: 0,
+            // No I/O permission bitmap, the offset is past the end of the segment
+            iomap_base: core::mem::size_of::<Self>() as u16,
+        }
+    }
+}
+
+impl Default for TaskStateSegment {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Loads the task register with the TSS descriptor at `selector`.
+///
+/// # Safety
+///
+/// `selector` must refer to an available 64-bit TSS descriptor in the loaded
+/// GDT, whose TSS lives for as long as it is used.
+pub unsafe fn load_tss(selector: u16) {
+    unsafe {
+        core::arch::asm!("ltr {0:x}", in(reg) selector, options(nostack, preserves_flags));
+    }
+}
@@ -263,10 +263,32 @@ mod tests {
         region.print_free();
     }
 
+    #[test]
+    fn blocks_are_naturally_aligned() {
+        let mut mem: Vec<u8> = Vec::new();
+        mem.resize(512 * 1024, 0);
+        let mem_ptr = mem.as_ptr().cast::<u8>();
+        // Only frame aligned, the region has to align its blocks itself
+        let offset = mem_ptr.align_offset(4096) + 4096;
+        let mem_ptr = unsafe { mem_ptr.add(offset) };
+        let mut allocator =
+            BuddyAllocator::<5, 4096>::new(mem_ptr as usize, mem.len() / 4096 - 2, 64).unwrap();
+        for order in [0, 3, 1, 2, 3, 0, 2] {
+            let addr = allocator.allocate_order(order).unwrap();
+            assert_eq!(
+                addr % (4096 << order),
+                0,
+                "order {} block at {:#x}",
+                order,
+                addr
+            );
+        }
+    }
+
     #[test]
     fn test_name() {
         let mut mem: Vec<u8> = Vec::new();
-        mem.resize(128 * 1024, 0);
+        mem.resize(256 * 1024, 0);
         let mem_ptr = mem.as_ptr().cast::<u8>();
         let offset = mem_ptr.align_offset(4096);
         let mem_ptr = unsafe { mem_ptr.add(offset) };
@@ -37,10 +37,21 @@ impl<const ORDERS: usize, const FRAME_SIZE: usize> Region<ORDERS, FRAME_SIZE> {
             return Err(RegionError::TooSmall);
         }
 
-        let (bitmaps, counts) = Self::create_bitmaps(base, layout);
+        // Blocks are aligned to their size, so the usable frames start at a
+        // multiple of the largest block
+        let largest_block = FRAME_SIZE << (layout.max_order - 1);
+        let usable_frames_base =
+            (base + layout.meta_frames * FRAME_SIZE).next_multiple_of(largest_block);
+        let reserved_frames = (usable_frames_base - base) / FRAME_SIZE;
+        if reserved_frames >= frames {
+            return Err(RegionError::TooSmall);
+        }
+
+        let usable_frames = frames - reserved_frames;
+        let (bitmaps, counts) = Self::create_bitmaps(base, layout, usable_frames);
         Ok(Self {
-            usable_frames_base: base + layout.meta_frames * FRAME_SIZE,
-            usable_frames: frames - layout.meta_frames,
+            usable_frames_base,
+            usable_frames,
             counts,
             bitmaps,
         })
@@ -152,6 +163,7 @@ impl<const ORDERS: usize, const FRAME_SIZE: usize> Region<ORDERS, FRAME_SIZE> {
     fn create_bitmaps(
         base: usize,
         layout: RegionLayout<ORDERS>,
+        usable_frames: usize,
     ) -> (RawVec<LayeredBitmap>, [usize; ORDERS]) {
         let mut bitmaps =
             unsafe { RawVec::from_raw_parts(base as *mut LayeredBitmap, layout.max_order) };
@@ -166,16 +178,18 @@ impl<const ORDERS: usize, const FRAME_SIZE: usize> Region<ORDERS, FRAME_SIZE> {
             bitmaps.push(bitmap).unwrap();
         }
 
+        // Free the usable frames with the largest blocks that fit, the
+        // bitmaps are sized for all frames of the region
         let mut counts = [0; ORDERS];
-        let mut index = 0;
+        let mut free_frames = 0;
         for order in (0..layout.max_order).rev() {
             let bitmap = &mut bitmaps[order];
-            for index in index..bitmap.len() {
-                bitmap.set(index);
+            while free_frames + (1 << order) <= usable_frames {
+                debug_assert!(free_frames >> order < bitmap.len());
+                bitmap.set(free_frames >> order);
                 counts[order] += 1;
+                free_frames += 1 << order;
             }
-
-            index = bitmap.len() * 2;
         }
 
         (bitmaps, counts)
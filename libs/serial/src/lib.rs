@@ -2,6 +2,10 @@
 
 #![no_std]
 
+use x86_64::port::Port;
+use x86_64::port::PortReadOnly;
+use x86_64::port::PortWriteOnly;
+
 pub const COM1_BASE: u16 = 0x03f8;
 
 pub struct SerialPort {
@@ -15,9 +19,9 @@ impl SerialPort {
 
     fn configure_baud_rate(&mut self, divisor: u16) {
         unsafe {
-            outb(self.line_command_port(), LINE_ENABLE_DLAB);
-            outb(self.data_port(), ((divisor >> 8) & 0x00FF) as _);
-            outb(self.data_port(), (divisor & 0x00FF) as _);
+            self.line_command_port().write(LINE_ENABLE_DLAB);
+            self.data_port().write(((divisor >> 8) & 0x00FF) as _);
+            self.data_port().write((divisor & 0x00FF) as _);
         }
     }
 
@@ -27,7 +31,7 @@ impl SerialPort {
          * Value:   | 0 | 0 | 0 0 0 | 0 | 1 1 | = 0x03
          */
         unsafe {
-            outb(self.line_command_port(), 0x03);
+            self.line_command_port().write(0x03);
         }
     }
 
@@ -37,7 +41,7 @@ impl SerialPort {
          * Value:   | 1 1 | 0  | 0 | 0   | 1   | 1   | 1 | = 0xC7
          */
         unsafe {
-            outb(self.fifo_command_port(), 0xC7);
+            self.fifo_command_port().write(0xC7);
         }
     }
 
@@ -47,13 +51,13 @@ impl SerialPort {
          * Value:   | 0 | 0 | 0  | 0  | 0   | 0   | 1   | 1 | = 0x03
          */
         unsafe {
-            outb(self.modem_command_port(), 0x03);
+            self.modem_command_port().write(0x03);
         }
     }
 
     fn is_transmit_fifo_empty(&self) -> bool {
         /* 0x20 = 0010 0000 */
-        unsafe { inb(self.line_status_port()) & 0x20 != 0 }
+        unsafe { self.line_status_port().read() & 0x20 != 0 }
     }
 
     pub fn serial_write(&mut self, data: &[u8]) {
@@ -66,7 +70,7 @@ impl SerialPort {
 
     fn serial_write_byte(&mut self, byte: u8) {
         unsafe {
-            outb(self.data_port(), byte);
+            self.data_port().write(byte);
         }
     }
 
@@ -77,48 +81,29 @@ impl SerialPort {
         self.configure_modem();
     }
 
-    fn data_port(&self) -> u16 {
-        self.base
+    fn data_port(&self) -> Port<u8> {
+        Port::new(self.base)
     }
 
-    fn fifo_command_port(&self) -> u16 {
-        self.base + 2
+    fn fifo_command_port(&self) -> PortWriteOnly<u8> {
+        PortWriteOnly::new(self.base + 2)
     }
 
-    fn line_command_port(&self) -> u16 {
-        self.base + 3
+    fn line_command_port(&self) -> PortWriteOnly<u8> {
+        PortWriteOnly::new(self.base + 3)
     }
 
-    fn modem_command_port(&self) -> u16 {
-        self.base + 4
+    fn modem_command_port(&self) -> PortWriteOnly<u8> {
+        PortWriteOnly::new(self.base + 4)
     }
 
-    fn line_status_port(&self) -> u16 {
-        self.base + 5
+    fn line_status_port(&self) -> PortReadOnly<u8> {
+        PortReadOnly::new(self.base + 5)
     }
 }
 
 const LINE_ENABLE_DLAB: u8 = 0x80;
 
-/// Write byte to port
-unsafe fn outb(port: u16, data: u8) {
-    core::arch::asm!("out dx, al",
-      in("al") data,
-      in("dx") port
-    );
-}
-
-unsafe fn inb(port: u16) -> u8 {
-    // Trust me bro
-    #[allow(unused_assignments)]
-    let mut data = 0;
-    core::arch::asm!("in al, dx",
-      in("dx") port,
-      inout("al") data
-    );
-    data
-}
-
 impl core::fmt::Write for SerialPort {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         self.serial_write(s.as_bytes());
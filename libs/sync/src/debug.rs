@@ -0,0 +1,107 @@
+// With the `lock-debug` feature each lock remembers which cpu holds it, and
+// taking it again on the same cpu panics instead of spinning forever. Locks
+// disable interrupts, so the holder can't be another thread on that cpu.
+// Which cpu is running comes from the function set with `set_cpu_index`,
+// nothing is checked before it is set.
+
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+static CPU_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the function that returns the index of the current cpu, used to
+/// detect recursive locking when the `lock-debug` feature is enabled. It may
+/// be called with any lock held, so it must not take locks itself.
+pub fn set_cpu_index(cpu_index: fn() -> usize) {
+    CPU_INDEX.store(cpu_index as usize, Ordering::Release);
+}
+
+#[cfg(feature = "lock-debug")]
+fn current_cpu() -> Option<usize> {
+    let cpu_index = CPU_INDEX.load(Ordering::Acquire);
+    if cpu_index == 0 {
+        return None;
+    }
+
+    let cpu_index = unsafe { core::mem::transmute::<usize, fn() -> usize>(cpu_index) };
+    Some(cpu_index())
+}
+
+/// The cpu holding a lock, only tracked with the `lock-debug` feature.
+#[derive(Debug)]
+pub(crate) struct Owner {
+    // Index of the holding cpu plus one, 0 when not held
+    #[cfg(feature = "lock-debug")]
+    cpu: AtomicUsize,
+}
+
+impl Owner {
+    pub(crate) const fn new() -> Self {
+        Self {
+            #[cfg(feature = "lock-debug")]
+            cpu: AtomicUsize::new(0),
+        }
+    }
+
+    /// Panics if the current cpu already holds the lock, called before
+    /// waiting for it.
+    #[inline]
+    pub(crate) fn check(&self) {
+        #[cfg(feature = "lock-debug")]
+        if let Some(cpu) = current_cpu() {
+            if self.cpu.load(Ordering::Relaxed) == cpu + 1 {
+                panic!("recursive locking on cpu {}", cpu);
+            }
+        }
+    }
+
+    #[inline]
+    pub(crate) fn acquire(&self) {
+        #[cfg(feature = "lock-debug")]
+        if let Some(cpu) = current_cpu() {
+            self.cpu.store(cpu + 1, Ordering::Relaxed);
+        }
+    }
+
+    #[inline]
+    pub(crate) fn release(&self) {
+        #[cfg(feature = "lock-debug")]
+        self.cpu.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(all(test, feature = "lock-debug"))]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    use crate::SpinLock;
+    use crate::TicketLock;
+
+    // Every test thread acts as its own cpu
+    fn thread_index() -> usize {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        thread_local! {
+            static INDEX: usize = NEXT.fetch_add(1, Ordering::Relaxed);
+        }
+        INDEX.with(|index| *index)
+    }
+
+    #[test]
+    #[should_panic(expected = "recursive locking")]
+    fn recursive_spin_lock_panics() {
+        super::set_cpu_index(thread_index);
+        let lock = SpinLock::new(());
+        let _guard = lock.lock();
+        let _ = lock.lock();
+    }
+
+    #[test]
+    #[should_panic(expected = "recursive locking")]
+    fn recursive_ticket_lock_panics() {
+        super::set_cpu_index(thread_index);
+        let lock = TicketLock::new(());
+        let _guard = lock.lock();
+        let _ = lock.lock();
+    }
+}
@@ -0,0 +1,28 @@
+// Every lock disables interrupts while it is held, so that an interrupt
+// handler taking the same lock can't deadlock against the code it
+// interrupted. Host tests run in user mode where `cli` faults, there the
+// interrupt flag is left alone.
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct IrqState {
+    enabled: bool,
+}
+
+/// Disables interrupts, returning whether they were enabled before.
+pub(crate) fn save_and_disable() -> IrqState {
+    #[cfg(target_os = "none")]
+    {
+        let enabled = x86_64::interrupts::are_enabled();
+        x86_64::interrupts::disable();
+        IrqState { enabled }
+    }
+    #[cfg(not(target_os = "none"))]
+    IrqState { enabled: false }
+}
+
+/// Re-enables interrupts if they were enabled when `state` was saved.
+pub(crate) fn restore(state: IrqState) {
+    if state.enabled {
+        x86_64::interrupts::enable();
+    }
+}
@@ -0,0 +1,57 @@
+#![cfg_attr(not(test), no_std)]
+#![deny(unsafe_op_in_unsafe_fn)]
+
+// The tests every lock shares. `$lock` and `$try_lock` take it exclusively,
+// `$is_locked` is checked where the lock has it
+#[cfg(test)]
+macro_rules! exclusive_lock_tests {
+    ($ty:ident, $lock:ident, $try_lock:ident $(, $is_locked:ident)?) => {
+        #[test]
+        fn try_lock_fails_while_locked() {
+            let lock = $ty::new(0);
+            let guard = lock.$lock();
+            $(assert!(lock.$is_locked());)?
+            assert!(lock.$try_lock().is_none());
+            drop(guard);
+            $(assert!(!lock.$is_locked());)?
+            assert!(lock.$try_lock().is_some());
+        }
+
+        #[test]
+        fn counts_from_many_threads() {
+            let lock = std::sync::Arc::new($ty::new(0));
+            let threads = (0..8)
+                .map(|_| {
+                    let lock = lock.clone();
+                    std::thread::spawn(move || {
+                        for _ in 0..1000 {
+                            *lock.$lock() += 1;
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert_eq!(*lock.$lock(), 8000);
+        }
+    };
+}
+
+mod debug;
+mod irq;
+mod once;
+mod rwlock;
+mod spin;
+mod ticket;
+
+pub use debug::set_cpu_index;
+pub use once::Lazy;
+pub use once::Once;
+pub use rwlock::RwLock;
+pub use rwlock::RwLockReadGuard;
+pub use rwlock::RwLockWriteGuard;
+pub use spin::SpinLock;
+pub use spin::SpinLockGuard;
+pub use ticket::TicketLock;
+pub use ticket::TicketLockGuard;
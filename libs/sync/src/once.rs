@@ -0,0 +1,189 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
+
+use crate::debug::Owner;
+use crate::irq;
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+/// A value that is initialised once, by whichever cpu gets there first, and
+/// only read afterwards. The others spin until it is ready.
+pub struct Once<T> {
+    state: AtomicU8,
+    // The cpu running the initialiser
+    owner: Owner,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send> Send for Once<T> {}
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+
+impl<T: fmt::Debug> fmt::Debug for Once<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.get() {
+            Some(value) => f.debug_tuple("Once").field(value).finish(),
+            None => f.debug_tuple("Once").field(&"<uninitialized>").finish(),
+        }
+    }
+}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(INCOMPLETE),
+            owner: Owner::new(),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Runs `f` to initialise the value if nothing has yet, and returns the
+    /// value. `f` runs with interrupts disabled.
+    pub fn call_once(&self, f: impl FnOnce() -> T) -> &T {
+        match self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+        {
+            Ok(_) => {
+                let irq = irq::save_and_disable();
+                self.owner.acquire();
+                unsafe { (*self.value.get()).write(f()) };
+                self.owner.release();
+                self.state.store(COMPLETE, Ordering::Release);
+                irq::restore(irq);
+            }
+            Err(RUNNING) => {
+                self.owner.check();
+                while self.state.load(Ordering::Acquire) != COMPLETE {
+                    core::hint::spin_loop();
+                }
+            }
+            Err(_) => {}
+        }
+
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+
+    /// The value, `None` until it has been initialised.
+    pub fn get(&self) -> Option<&T> {
+        match self.state.load(Ordering::Acquire) {
+            COMPLETE => Some(unsafe { (*self.value.get()).assume_init_ref() }),
+            _ => None,
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+impl<T> Default for Once<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for Once<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == COMPLETE {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/// A value that is initialised by `F` on first use, for statics that can't be
+/// built in a const context.
+pub struct Lazy<T, F = fn() -> T> {
+    once: Once<T>,
+    init: UnsafeCell<Option<F>>,
+}
+
+// `init` is only taken by the cpu that wins the race in `Once`
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+impl<T: fmt::Debug, F> fmt::Debug for Lazy<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Lazy").field(&self.once).finish()
+    }
+}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    pub const fn new(init: F) -> Self {
+        Self {
+            once: Once::new(),
+            init: UnsafeCell::new(Some(init)),
+        }
+    }
+
+    /// Initialises the value if that hasn't happened yet.
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(|| {
+            let init = unsafe { (*this.init.get()).take() };
+            init.expect("Lazy initialiser already taken")()
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        Self::force(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn initialises_once() {
+        let once = Once::new();
+        assert!(once.get().is_none());
+        assert_eq!(*once.call_once(|| 1), 1);
+        assert_eq!(*once.call_once(|| 2), 1);
+        assert_eq!(once.get(), Some(&1));
+    }
+
+    #[test]
+    fn initialises_once_from_many_threads() {
+        let once = Arc::new(Once::new());
+        let calls = Arc::new(AtomicUsize::new(0));
+        let threads = (0..8)
+            .map(|thread| {
+                let once = once.clone();
+                let calls = calls.clone();
+                std::thread::spawn(move || {
+                    *once.call_once(|| {
+                        calls.fetch_add(1, Ordering::Relaxed);
+                        thread
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        let values = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert!(values.iter().all(|&value| value == values[0]));
+    }
+
+    #[test]
+    fn lazy_runs_initialiser_on_first_use() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        static LAZY: Lazy<usize> = Lazy::new(|| CALLS.fetch_add(1, Ordering::Relaxed) + 10);
+        assert_eq!(CALLS.load(Ordering::Relaxed), 0);
+        assert_eq!(*LAZY, 10);
+        assert_eq!(*LAZY, 10);
+        assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,252 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::Deref;
+use core::ops::DerefMut;
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+
+use crate::debug::Owner;
+use crate::irq;
+use crate::irq::IrqState;
+
+// The state holds the writer bit, the bit of a waiting writer and the number
+// of readers above them
+const WRITER: usize = 1;
+const WRITER_WAITING: usize = 2;
+const READER: usize = 4;
+
+/// A spinning reader-writer lock that disables interrupts while held. A
+/// waiting writer keeps new readers out, so readers must not take the lock
+/// recursively.
+pub struct RwLock<T> {
+    state: AtomicUsize,
+    // The writer, readers aren't tracked
+    owner: Owner,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+impl<T: fmt::Debug> fmt::Debug for RwLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_read() {
+            Some(guard) => f.debug_tuple("RwLock").field(&*guard).finish(),
+            None => f.debug_tuple("RwLock").field(&"<locked>").finish(),
+        }
+    }
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            owner: Owner::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        let irq = irq::save_and_disable();
+        self.owner.check();
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & (WRITER | WRITER_WAITING) == 0
+                && self
+                    .state
+                    .compare_exchange_weak(
+                        state,
+                        state + READER,
+                        Ordering::Acquire,
+                        Ordering::Relaxed,
+                    )
+                    .is_ok()
+            {
+                return RwLockReadGuard { lock: self, irq };
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        let irq = irq::save_and_disable();
+        let state = self.state.load(Ordering::Relaxed);
+        if state & (WRITER | WRITER_WAITING) == 0
+            && self
+                .state
+                .compare_exchange(state, state + READER, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        {
+            return Some(RwLockReadGuard { lock: self, irq });
+        }
+        irq::restore(irq);
+        None
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        let irq = irq::save_and_disable();
+        self.owner.check();
+        loop {
+            let state = self.state.load(Ordering::Relaxed);
+            if state & !WRITER_WAITING == 0 {
+                // Taking the lock clears the waiting bit, other waiting
+                // writers set it again
+                if self
+                    .state
+                    .compare_exchange_weak(state, WRITER, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    break;
+                }
+            } else if state & WRITER_WAITING == 0 {
+                self.state.fetch_or(WRITER_WAITING, Ordering::Relaxed);
+            }
+            core::hint::spin_loop();
+        }
+        self.owner.acquire();
+        RwLockWriteGuard { lock: self, irq }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        let irq = irq::save_and_disable();
+        if self
+            .state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            irq::restore(irq);
+            return None;
+        }
+        self.owner.acquire();
+        Some(RwLockWriteGuard { lock: self, irq })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    irq: IrqState,
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.state.fetch_sub(READER, Ordering::Release);
+        irq::restore(self.irq);
+    }
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+    irq: IrqState,
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.owner.release();
+        self.lock.state.fetch_and(!WRITER, Ordering::Release);
+        irq::restore(self.irq);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    exclusive_lock_tests!(RwLock, write, try_write);
+
+    #[test]
+    fn readers_share_the_lock() {
+        let lock = RwLock::new(1);
+        let first = lock.read();
+        let second = lock.read();
+        assert_eq!(*first + *second, 2);
+        assert!(lock.try_write().is_none());
+        drop(first);
+        drop(second);
+        assert!(lock.try_write().is_some());
+    }
+
+    #[test]
+    fn writer_excludes_readers() {
+        let lock = RwLock::new(1);
+        let mut writer = lock.write();
+        *writer = 2;
+        assert!(lock.try_read().is_none());
+        assert!(lock.try_write().is_none());
+        drop(writer);
+        assert_eq!(*lock.read(), 2);
+    }
+
+    #[test]
+    fn waiting_writer_keeps_new_readers_out() {
+        let lock = Arc::new(RwLock::new(1));
+        let reader = lock.read();
+        let writer = {
+            let lock = lock.clone();
+            std::thread::spawn(move || *lock.write() = 2)
+        };
+        while lock.state.load(Ordering::Relaxed) & WRITER_WAITING == 0 {
+            core::hint::spin_loop();
+        }
+
+        assert!(lock.try_read().is_none());
+        assert_eq!(*reader, 1);
+        drop(reader);
+        writer.join().unwrap();
+        assert_eq!(lock.state.load(Ordering::Relaxed), 0);
+        assert_eq!(*lock.read(), 2);
+    }
+
+    #[test]
+    fn writers_from_many_threads() {
+        let lock = Arc::new(RwLock::new(0));
+        let threads = (0..8)
+            .map(|thread| {
+                let lock = lock.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        match thread % 2 {
+                            0 => *lock.write() += 1,
+                            _ => assert!(*lock.read() <= 4000),
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*lock.read(), 4000);
+    }
+}
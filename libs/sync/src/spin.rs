@@ -0,0 +1,110 @@
+use core::cell::UnsafeCell;
+use core::fmt;
+use core::ops::Deref;
+use core::ops::DerefMut;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+
+use crate::debug::Owner;
+use crate::irq;
+use crate::irq::IrqState;
+
+/// A test-and-set spinlock that disables interrupts while held, the guard
+/// restores the previous interrupt state. Not fair, see `TicketLock`.
+pub struct SpinLock<T> {
+    locked: AtomicBool,
+    owner: Owner,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for SpinLock<T> {}
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T: fmt::Debug> fmt::Debug for SpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.try_lock() {
+            Some(guard) => f.debug_tuple("SpinLock").field(&*guard).finish(),
+            None => f.debug_tuple("SpinLock").field(&"<locked>").finish(),
+        }
+    }
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            owner: Owner::new(),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        let irq = irq::save_and_disable();
+        self.owner.check();
+        while self.locked.swap(true, Ordering::Acquire) {
+            // Wait with plain loads, so that the cache line isn't bounced
+            // between the waiting cpus
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+        self.owner.acquire();
+        SpinLockGuard { lock: self, irq }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let irq = irq::save_and_disable();
+        if self.locked.swap(true, Ordering::Acquire) {
+            irq::restore(irq);
+            return None;
+        }
+        self.owner.acquire();
+        Some(SpinLockGuard { lock: self, irq })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    irq: IrqState,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.owner.release();
+        self.lock.locked.store(false, Ordering::Release);
+        irq::restore(self.irq);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    exclusive_lock_tests!(SpinLock, lock, try_lock, is_locked);
+}